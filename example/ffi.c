@@ -1,8 +1,6 @@
 #include <stdio.h>
 #include <stdint.h>
 #include <pcap.h>
-#include <netinet/ip.h>
-#include <netinet/tcp.h>
 
 #include "../include/memerge.h"
 
@@ -33,7 +31,7 @@ int simple(void) {
     printf("task new_with_parser smtp. return... ok\n");
 
     printf("task run. pktlen: %lu\n", sizeof(pkt));
-    task_run(task, pkt, sizeof(pkt), C2s, 999);
+    task_run(task, pkt, sizeof(pkt), DLT_EN10MB, C2s, 999, NULL);
     printf("task run. 222\n");
 
     meta = task_get_meta(task);
@@ -53,40 +51,14 @@ int simple(void) {
 }
 
 #define SMTP_PCAP "../tests/smtp.pcap"
-task_t *task = NULL;
+flow_table_t *ft = NULL;
+int           datalink = DLT_EN10MB;
+int           ignored[PktBadCsum + 1];
 
-int need_pkt(uint8_t *pkt) {
-    struct ip     *ip_header;
-    struct tcphdr *tcp_header;
-
-    ip_header = (struct ip *)(pkt + 14);
-    if (ip_header->ip_p == IPPROTO_TCP) {
-        tcp_header = (struct tcphdr *)(pkt + 14 + ip_header->ip_hl * 4);
-        if (ntohs(tcp_header->th_dport) == 25) {
-            return 1;
-        }
-    }
-    return 0; 
-}
-
-void packet_handler(u_char *user_data, const struct pcap_pkthdr *pkthdr, const u_char *packet) {
-    meta_t       *meta;
+void handle_meta(meta_t *meta) {
     char         *meta_user;
     MetaSmtpType  smtp_type;
-    
-    if (task == NULL) {
-        printf("packet_handler. task is null, return.\n");
-        return;
-    }
-    if (need_pkt((uint8_t *)packet) == 0) {
-        return;
-    }
-    
-    task_run(task, packet, pkthdr->len, C2s, 999);    
-    meta = task_get_meta(task);
-    if (meta == NULL) {
-        return;
-    }
+
     if (meta_protocol(meta) != Smtp) {
         return;
     }
@@ -111,14 +83,37 @@ void packet_handler(u_char *user_data, const struct pcap_pkthdr *pkthdr, const u
     default:
         break;
     }
-    
-    meta_free(meta);
+}
+
+void packet_handler(u_char *user_data, const struct pcap_pkthdr *pkthdr, const u_char *packet) {
+    meta_t     *meta;
+    uint64_t    ts;
+    PktErrCode  err;
+    size_t      err_offset;
+
+    if (ft == NULL) {
+        printf("packet_handler. flow table is null, return.\n");
+        return;
+    }
+
+    /* 流表自己区分连接和方向 */
+    ts = (uint64_t)pkthdr->ts.tv_sec * 1000 + pkthdr->ts.tv_usec / 1000;
+    err = flow_table_run(ft, packet, pkthdr->caplen, datalink, ts, &err_offset);
+    ignored[err]++;
+    if (err > PktUnsupportedLink) {
+        printf("ignored packet. reason: %d, offset: %zu\n", err, err_offset);
+    }
+    while ((meta = flow_table_get_meta(ft)) != NULL) {
+        handle_meta(meta);
+        meta_free(meta);
+    }
+    flow_table_timeout(ft, ts);
 }
 
 int main(void) {
-    task = task_new_with_parser(Smtp);
-    if (task == NULL) {
-        fprintf(stderr, "Error task_init_parser.\n");
+    ft = flow_table_new(Smtp);
+    if (ft == NULL) {
+        fprintf(stderr, "Error flow_table_new.\n");
         return 1;
     }
     
@@ -128,12 +123,21 @@ int main(void) {
         fprintf(stderr, "Error opening pcap file: %s\n", errbuf);
         return 1;
     }
+    datalink = pcap_datalink(pcap);
     if (pcap_loop(pcap, 0, packet_handler, NULL) < 0) {
         fprintf(stderr, "Error in pcap_loop\n");
         return 1;
     }
 
-    task_free(task);
+    for (int i = PktInvalidArg; i <= PktBadCsum; i++) {
+        if (ignored[i]) {
+            printf("ignored packets. reason: %d, count: %d\n", i, ignored[i]);
+        }
+    }
+    printf("flows left: %zu\n", flow_table_len(ft));
+    printf("packets dropped by flow limit: %lu\n", (unsigned long)flow_table_full_drops(ft));
+
+    flow_table_free(ft);
     pcap_close(pcap);
     return 0;;
 }
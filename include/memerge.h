@@ -6,6 +6,7 @@
 
 typedef struct task_s task_t;
 typedef struct meta_s meta_t;
+typedef struct flow_table_s flow_table_t;
 
 typedef enum {
     Smtp,
@@ -17,9 +18,41 @@ typedef enum {
   C2s,
   S2c,
   BiDir,
-  Unknown
+  Unknown       /* BiDir和Unknown：由task根据syn，服务端口，先发包的一端判断方向 */
 } PacketDir;
 
+typedef enum {
+    CsumOff,
+    CsumAccept,
+    CsumFlag,
+    CsumDrop
+} ChecksumPolicy;
+
+typedef enum {
+    TcpInit,
+    TcpSynSent,
+    TcpSynRcvd,
+    TcpEstablished,
+    TcpFinWait,         /* 一个方向发了fin */
+    TcpClosed,          /* 两个方向都发了fin */
+    TcpReset,
+} TcpState;
+
+typedef enum {
+    PktOk,
+    PktInvalidArg,
+    PktUnsupportedLink,
+    PktTruncated,
+    PktUnsupportedEtherType,
+    PktNonIp,
+    PktNonTcpUdp,
+    PktOversize,
+    PktMalformedOptions,
+    PktFragmented,
+    PktTunnelDepth,
+    PktBadCsum,
+} PktErrCode;
+
 typedef enum {
     User,
     Pass,
@@ -33,8 +66,28 @@ extern task_t       *task_new();
 extern void          task_free(task_t *task);
 extern task_t       *task_new_with_parser(ParserType parser_type);
 extern task_t       *task_init_parser(task_t *task, ParserType parser_type);
-extern void          task_run(task_t *task, const u_int8_t *pkt, size_t pkt_len, PacketDir pkt_dir, uint64_t ts);
+extern void          task_set_csum_policy(task_t *task, ChecksumPolicy policy);
+extern void          task_set_timeout(task_t *task, uint64_t timeout);
+extern void          task_timeout(task_t *task, uint64_t now);
+extern TcpState      task_conn_state(const task_t *task);
+extern int           task_is_midstream(const task_t *task);
+extern int           task_is_rejected(const task_t *task);
+extern PktErrCode    task_run(task_t *task, const u_int8_t *pkt, size_t pkt_len, int datalink, PacketDir pkt_dir, uint64_t ts, size_t *err_offset);
 extern meta_t       *task_get_meta(task_t *task);
+extern flow_table_t *flow_table_new(ParserType parser_type);
+extern void          flow_table_free(flow_table_t *ft);
+extern void          flow_table_set_csum_policy(flow_table_t *ft, ChecksumPolicy policy);
+extern void          flow_table_set_strm_timeout(flow_table_t *ft, uint64_t timeout);
+extern void          flow_table_set_midstream_wait(flow_table_t *ft, uint64_t wait);
+extern void          flow_table_set_cache_limit(flow_table_t *ft, size_t max_pkts, size_t max_bytes);
+extern void          flow_table_set_mem_budget(flow_table_t *ft, size_t limit);
+extern size_t        flow_table_mem_used(const flow_table_t *ft);
+extern uint64_t      flow_table_full_drops(const flow_table_t *ft);
+extern void          flow_table_set_timeout(flow_table_t *ft, uint64_t timeout);
+extern PktErrCode    flow_table_run(flow_table_t *ft, const u_int8_t *pkt, size_t pkt_len, int datalink, uint64_t ts, size_t *err_offset);
+extern void          flow_table_timeout(flow_table_t *ft, uint64_t now);
+extern size_t        flow_table_len(const flow_table_t *ft);
+extern meta_t       *flow_table_get_meta(flow_table_t *ft);
 extern void          meta_free(meta_t *meta);
 extern ParserType    meta_protocol(meta_t *meta);
 extern MetaSmtpType  smtp_meta_type(meta_t *meta);
@@ -0,0 +1,100 @@
+use crate::{Packet, PktDirection};
+
+// 连接的状态，被动观察两个方向的包得到
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConnState {
+    #[default]
+    Init,        // 还没有见到包
+    SynSent,     // 见到了客户端的syn
+    SynRcvd,     // 见到了服务器的syn+ack
+    Established, // 握手完成，或者中途接入
+    FinWait,     // 一个方向发了fin
+    Closed,      // 两个方向都发了fin
+    Reset,       // 被有效的rst结束
+}
+
+// 跟踪握手和挥手。rst是否有效由流判断，这里只记录结果
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Conn {
+    state: ConnState,
+    client_isn: Option<u32>,
+    server_isn: Option<u32>,
+    midstream: bool,   // 第一个包不是syn
+    established: bool, // 进入过Established
+    c2s_fin: bool,
+    s2c_fin: bool,
+}
+
+impl Conn {
+    // dir已经确定，只能是Client2Server或者Server2Client
+    pub(crate) fn update(&mut self, pkt: &Packet, dir: &PktDirection) {
+        if self.state == ConnState::Reset || pkt.rst() == Some(true) {
+            return;
+        }
+        let c2s = *dir == PktDirection::Client2Server;
+        let syn = pkt.syn();
+        let ack = pkt.ack() == Some(true);
+        if self.state == ConnState::Init && !syn {
+            self.midstream = true;
+        }
+
+        match (syn, ack, c2s) {
+            (true, false, true) => {
+                self.client_isn = Some(pkt.seq());
+                if self.state == ConnState::Init {
+                    self.state = ConnState::SynSent;
+                }
+            }
+            (true, true, false) => {
+                self.server_isn = Some(pkt.seq());
+                if let Some(ack) = pkt.ack_seq() {
+                    self.client_isn.get_or_insert(ack.wrapping_sub(1));
+                }
+                if matches!(self.state, ConnState::Init | ConnState::SynSent) {
+                    self.state = ConnState::SynRcvd;
+                }
+            }
+            (false, _, _) if matches!(self.state, ConnState::Init | ConnState::SynSent | ConnState::SynRcvd) => {
+                self.state = ConnState::Established;
+            }
+            _ => {}
+        }
+        if self.state == ConnState::Established {
+            self.established = true;
+        }
+
+        if pkt.fin() {
+            if c2s {
+                self.c2s_fin = true;
+            } else {
+                self.s2c_fin = true;
+            }
+            self.state = if self.c2s_fin && self.s2c_fin { ConnState::Closed } else { ConnState::FinWait };
+        }
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.state = ConnState::Reset;
+    }
+
+    pub(crate) fn state(&self) -> ConnState {
+        self.state
+    }
+
+    pub(crate) fn isn(&self, dir: &PktDirection) -> Option<u32> {
+        match dir {
+            PktDirection::Client2Server => self.client_isn,
+            PktDirection::Server2Client => self.server_isn,
+            _ => None,
+        }
+    }
+
+    pub(crate) fn is_midstream(&self) -> bool {
+        self.midstream
+    }
+
+    // 握手没有完成就被rst，一般是服务器拒绝了连接
+    pub(crate) fn is_rejected(&self) -> bool {
+        self.state == ConnState::Reset && !self.established
+    }
+}
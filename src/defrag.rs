@@ -0,0 +1,311 @@
+use etherparse::{Ipv4Header, Ipv6Header, SerializedSize, ether_type, ip_number};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::rc::Rc;
+use crate::{LinkType, Packet};
+
+const MAX_FRAG_DGRAMS: usize = 256;      // 同时重组的分片报文数量
+const MAX_FRAGS: usize = 64;             // 每个报文最多的分片数量
+const MAX_DGRAM_LEN: usize = 65535;      // 重组后ip载荷的最大长度
+const DEFAULT_FRAG_TIMEOUT: u128 = 30_000; // 毫秒
+
+// 分片重叠时，重叠部分采用哪个分片的数据
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FragPolicy {
+    #[default]
+    First, // 先到的分片优先
+    Last,  // 后到的分片优先
+    Bsd,   // 起始偏移小的优先，相同则先到的优先
+    Linux, // 起始偏移小的优先，相同则后到的优先
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct FragKey {
+    src: IpAddr,
+    dst: IpAddr,
+    id: u32,
+    proto: u8,
+}
+
+#[derive(Debug)]
+struct Frag {
+    offset: usize,
+    data: Vec<u8>,
+}
+
+// 第一个分片中，分片载荷之前的部分：链路层，ip头，ipv6不可分片的扩展头
+#[derive(Debug)]
+struct FragHead {
+    data: Vec<u8>,
+    ip_offset: usize,
+    nh_offset: usize, // ipv6中指向分片头的next header字段的位置
+}
+
+#[derive(Debug)]
+struct FragBuf {
+    start_ts: u128,
+    head: Option<FragHead>,
+    frags: Vec<Frag>,
+    total: Option<usize>, // 收到最后一个分片后才知道总长度
+}
+
+// 一个分片的解析结果
+struct FragInfo {
+    key: FragKey,
+    offset: usize,
+    more: bool,
+    data_start: usize,
+    data_end: usize,
+    head_end: usize,
+    ip_offset: usize,
+    nh_offset: usize,
+}
+
+#[derive(Debug)]
+pub struct Defrag {
+    bufs: HashMap<FragKey, FragBuf>,
+    timeout: u128,
+    policy: FragPolicy,
+}
+
+impl Defrag {
+    pub fn new() -> Self {
+        Defrag {
+            bufs: HashMap::new(),
+            timeout: DEFAULT_FRAG_TIMEOUT,
+            policy: FragPolicy::default(),
+        }
+    }
+
+    pub fn set_timeout(&mut self, timeout: u128) {
+        self.timeout = timeout;
+    }
+
+    pub fn set_policy(&mut self, policy: FragPolicy) {
+        self.policy = policy;
+    }
+
+    // 正在重组的报文数量
+    pub fn len(&self) -> usize {
+        self.bufs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.bufs.clear();
+    }
+
+    // 在Packet::decode之前调用。不是分片的包原样返回；分片的包缓存起来，
+    // 收齐之后返回重组好的包；否则返回None
+    pub fn push(&mut self, pkt: Rc<Packet>) -> Option<Rc<Packet>> {
+        let info = match frag_info(&pkt, pkt.link_type()) {
+            Some(info) => info,
+            None => return Some(pkt),
+        };
+        if info.data_end - info.data_start + info.offset > MAX_DGRAM_LEN {
+            return None;
+        }
+
+        if let Some(buf) = self.bufs.get(&info.key) {
+            if pkt.timestamp.saturating_sub(buf.start_ts) > self.timeout {
+                self.bufs.remove(&info.key);
+            }
+        }
+        if !self.bufs.contains_key(&info.key) && self.bufs.len() >= MAX_FRAG_DGRAMS {
+            return None;
+        }
+
+        let buf = self.bufs.entry(info.key.clone()).or_insert_with(|| FragBuf {
+            start_ts: pkt.timestamp,
+            head: None,
+            frags: Vec::new(),
+            total: None,
+        });
+        if buf.frags.len() >= MAX_FRAGS {
+            return None;
+        }
+
+        if info.offset == 0 && buf.head.is_none() {
+            buf.head = Some(FragHead {
+                data: pkt[..info.head_end].to_vec(),
+                ip_offset: info.ip_offset,
+                nh_offset: info.nh_offset,
+            });
+        }
+        if !info.more {
+            buf.total = Some(info.offset + info.data_end - info.data_start);
+        }
+        buf.frags.push(Frag {
+            offset: info.offset,
+            data: pkt[info.data_start..info.data_end].to_vec(),
+        });
+
+        let payload = buf.assemble(self.policy)?;
+        let buf = self.bufs.remove(&info.key)?;
+        let data = buf.head?.build(&info.key, &payload)?;
+        Packet::new_with_link(pkt.timestamp, data.len(), &data, pkt.link_type()).ok()
+    }
+
+    // 清除超时的分片缓存。now和Packet::timestamp单位相同
+    pub fn timeout(&mut self, now: u128) {
+        let timeout = self.timeout;
+        self.bufs.retain(|_, buf| now.saturating_sub(buf.start_ts) <= timeout);
+    }
+}
+
+impl Default for Defrag {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FragBuf {
+    // 所有分片都到齐了，按重叠策略拼出ip载荷
+    fn assemble(&self, policy: FragPolicy) -> Option<Vec<u8>> {
+        let total = self.total?;
+        self.head.as_ref()?;
+
+        let mut payload = vec![0; total];
+        let mut owner: Vec<Option<usize>> = vec![None; total];
+        for (index, frag) in self.frags.iter().enumerate() {
+            for (i, byte) in frag.data.iter().enumerate() {
+                let pos = frag.offset + i;
+                if pos >= total {
+                    break;
+                }
+                let replace = match owner[pos] {
+                    None => true,
+                    Some(old) => {
+                        let old_offset = self.frags[old].offset;
+                        match policy {
+                            FragPolicy::First => false,
+                            FragPolicy::Last => true,
+                            FragPolicy::Bsd => frag.offset < old_offset,
+                            FragPolicy::Linux => frag.offset <= old_offset,
+                        }
+                    }
+                };
+                if replace {
+                    payload[pos] = *byte;
+                    owner[pos] = Some(index);
+                }
+            }
+        }
+
+        if owner.iter().any(|o| o.is_none()) {
+            return None;
+        }
+        Some(payload)
+    }
+}
+
+impl FragHead {
+    // 第一个分片的头部加上重组好的载荷，修正ip头中的长度和分片字段
+    fn build(self, key: &FragKey, payload: &[u8]) -> Option<Vec<u8>> {
+        let mut data = self.data;
+        let ip_offset = self.ip_offset;
+        match key.src {
+            IpAddr::V4(_) => {
+                let (mut ipv4h, _) = Ipv4Header::from_slice(&data[ip_offset..]).ok()?;
+                ipv4h.more_fragments = false;
+                ipv4h.fragments_offset = 0;
+                ipv4h.set_payload_len(payload.len()).ok()?;
+                data.truncate(ip_offset);
+                ipv4h.write(&mut data).ok()?;
+            }
+            IpAddr::V6(_) => {
+                let payload_length = data.len() - ip_offset - Ipv6Header::SERIALIZED_SIZE + payload.len();
+                let payload_length: u16 = payload_length.try_into().ok()?;
+                data[ip_offset + 4..ip_offset + 6].copy_from_slice(&payload_length.to_be_bytes());
+                data[self.nh_offset] = key.proto;
+            }
+        }
+        data.extend_from_slice(payload);
+        Some(data)
+    }
+}
+
+// 解析链路层和vlan，返回ip头的位置和ether type
+fn ip_offset(data: &[u8], link_type: LinkType) -> Option<(usize, u16)> {
+    let (mut offset, mut ether) = link_type.network(data)?;
+    while matches!(ether, ether_type::VLAN_TAGGED_FRAME | ether_type::PROVIDER_BRIDGING | ether_type::VLAN_DOUBLE_TAGGED_FRAME) {
+        let tag = data.get(offset..offset + 4)?;
+        ether = u16::from_be_bytes([tag[2], tag[3]]);
+        offset += 4;
+    }
+    Some((offset, ether))
+}
+
+// 如果是ip分片，返回分片信息，否则返回None
+fn frag_info(data: &[u8], link_type: LinkType) -> Option<FragInfo> {
+    let (ip_offset, ether) = ip_offset(data, link_type)?;
+    match ether {
+        ether_type::IPV4 => {
+            let (ipv4h, _) = Ipv4Header::from_slice(&data[ip_offset..]).ok()?;
+            if !ipv4h.more_fragments && ipv4h.fragments_offset == 0 {
+                return None;
+            }
+            let head_end = ip_offset + ipv4h.header_len();
+            Some(FragInfo {
+                key: FragKey {
+                    src: IpAddr::V4(Ipv4Addr::from(ipv4h.source)),
+                    dst: IpAddr::V4(Ipv4Addr::from(ipv4h.destination)),
+                    id: ipv4h.identification.into(),
+                    proto: ipv4h.protocol,
+                },
+                offset: ipv4h.fragments_offset as usize * 8,
+                more: ipv4h.more_fragments,
+                data_start: head_end,
+                data_end: (ip_offset + ipv4h.total_len() as usize).min(data.len()),
+                head_end,
+                ip_offset,
+                nh_offset: 0,
+            })
+        }
+        ether_type::IPV6 => {
+            let (ipv6h, _) = Ipv6Header::from_slice(&data[ip_offset..]).ok()?;
+            let ip_end = (ip_offset + Ipv6Header::SERIALIZED_SIZE + ipv6h.payload_length as usize).min(data.len());
+
+            // 跳过分片头之前的扩展头
+            let mut nh_offset = ip_offset + 6;
+            let mut next = ipv6h.next_header;
+            let mut pos = ip_offset + Ipv6Header::SERIALIZED_SIZE;
+            while matches!(next, ip_number::IPV6_HOP_BY_HOP | ip_number::IPV6_ROUTE | ip_number::IPV6_DEST_OPTIONS) {
+                let ext = data.get(pos..pos + 2)?;
+                nh_offset = pos;
+                next = ext[0];
+                pos += (ext[1] as usize + 1) * 8;
+            }
+            if next != ip_number::IPV6_FRAG {
+                return None;
+            }
+
+            let frag = data.get(pos..pos + 8)?;
+            let offset_flags = u16::from_be_bytes([frag[2], frag[3]]);
+            let offset = (offset_flags >> 3) as usize * 8;
+            let more = offset_flags & 1 == 1;
+            if !more && offset == 0 {
+                return None;
+            }
+            Some(FragInfo {
+                key: FragKey {
+                    src: IpAddr::V6(Ipv6Addr::from(ipv6h.source)),
+                    dst: IpAddr::V6(Ipv6Addr::from(ipv6h.destination)),
+                    id: u32::from_be_bytes([frag[4], frag[5], frag[6], frag[7]]),
+                    proto: frag[0],
+                },
+                offset,
+                more,
+                data_start: pos + 8,
+                data_end: ip_end.max(pos + 8),
+                head_end: pos,
+                ip_offset,
+                nh_offset,
+            })
+        }
+        _ => None,
+    }
+}
@@ -1,7 +1,7 @@
 extern crate libc;
 use std::ptr;
-use crate::{Task, PktDirection, Packet, Meta, smtp::{SmtpParser, MetaSmtp}};
-use std::ffi::{CString, c_char};
+use crate::{Task, FlowTable, PktDirection, Packet, PacketError, LinkType, CsumPolicy, ConnState, Meta, smtp::{SmtpParser, MetaSmtp}};
+use std::ffi::{CString, c_char, c_int};
 
 #[repr(C)] #[allow(dead_code)]
 pub enum ParserType {
@@ -29,13 +29,100 @@ impl From<PacketDir> for PktDirection {
     }
 }
 
+#[repr(C)]
+pub enum ChecksumPolicy {
+    Off,
+    Accept,
+    Flag,
+    Drop
+}
+
+impl From<ChecksumPolicy> for CsumPolicy {
+    fn from(policy: ChecksumPolicy) -> CsumPolicy {
+        match policy {
+            ChecksumPolicy::Off => CsumPolicy::Off,
+            ChecksumPolicy::Accept => CsumPolicy::Accept,
+            ChecksumPolicy::Flag => CsumPolicy::Flag,
+            ChecksumPolicy::Drop => CsumPolicy::Drop,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, PartialEq, Eq)]
+pub enum TcpState {
+    Init,
+    SynSent,
+    SynRcvd,
+    Established,
+    FinWait,
+    Closed,
+    Reset,
+}
+
+impl From<ConnState> for TcpState {
+    fn from(state: ConnState) -> TcpState {
+        match state {
+            ConnState::Init => TcpState::Init,
+            ConnState::SynSent => TcpState::SynSent,
+            ConnState::SynRcvd => TcpState::SynRcvd,
+            ConnState::Established => TcpState::Established,
+            ConnState::FinWait => TcpState::FinWait,
+            ConnState::Closed => TcpState::Closed,
+            ConnState::Reset => TcpState::Reset,
+        }
+    }
+}
+
+// task_run的返回值，用来统计被忽略的包
+#[repr(C)]
+#[derive(Debug, PartialEq, Eq)]
+pub enum PktErrCode {
+    Ok,
+    InvalidArg,
+    UnsupportedLink,
+    Truncated,
+    UnsupportedEtherType,
+    NonIp,
+    NonTcpUdp,
+    Oversize,
+    MalformedOptions,
+    Fragmented,
+    TunnelDepth,
+    BadCsum,
+}
+
+// 包被忽略时，err_offset不为NULL则写入出错的位置，相对包的开头
+fn pkt_err(err: PacketError, err_offset: *mut usize) -> PktErrCode {
+    if !err_offset.is_null() {
+        unsafe { *err_offset = err.offset(); }
+    }
+    err.into()
+}
+
+impl From<PacketError> for PktErrCode {
+    fn from(err: PacketError) -> PktErrCode {
+        match err {
+            PacketError::Truncated { .. } => PktErrCode::Truncated,
+            PacketError::UnsupportedEtherType { .. } => PktErrCode::UnsupportedEtherType,
+            PacketError::NonIp { .. } => PktErrCode::NonIp,
+            PacketError::NonTcpUdp { .. } => PktErrCode::NonTcpUdp,
+            PacketError::Oversize { .. } => PktErrCode::Oversize,
+            PacketError::MalformedOptions { .. } => PktErrCode::MalformedOptions,
+            PacketError::Fragmented { .. } => PktErrCode::Fragmented,
+            PacketError::TunnelDepth { .. } => PktErrCode::TunnelDepth,
+            PacketError::BadCsum { .. } => PktErrCode::BadCsum,
+        }
+    }
+}
+
 #[no_mangle]
-pub extern fn task_new() -> *mut Task {
+pub extern "C" fn task_new() -> *mut Task {
     Box::into_raw(Box::new(Task::new()))
 }
 
 #[no_mangle]
-pub extern fn task_free(ptr: *mut Task) {
+pub extern "C" fn task_free(ptr: *mut Task) {
     if ptr.is_null()  {
         return;
     }
@@ -44,7 +131,7 @@ pub extern fn task_free(ptr: *mut Task) {
 }
 
 #[no_mangle]
-pub extern fn task_new_with_parser(parser_type: ParserType) -> *mut Task{
+pub extern "C" fn task_new_with_parser(parser_type: ParserType) -> *mut Task{
     match parser_type {
         ParserType::Smtp => {
             Box::into_raw(Box::new(Task::new_with_parser(SmtpParser)))
@@ -56,7 +143,7 @@ pub extern fn task_new_with_parser(parser_type: ParserType) -> *mut Task{
 }
 
 #[no_mangle]
-pub extern fn task_init_parser(task_ptr: *mut Task, parser_type: ParserType) -> *mut Task {
+pub extern "C" fn task_init_parser(task_ptr: *mut Task, parser_type: ParserType) -> *mut Task {
     if task_ptr.is_null()  {
         return task_ptr;
     }
@@ -74,23 +161,252 @@ pub extern fn task_init_parser(task_ptr: *mut Task, parser_type: ParserType) ->
 }
 
 #[no_mangle]
-pub extern fn task_run(task_ptr: *mut Task, pkt: *const u8, pkt_len: usize, pkt_dir: PacketDir, ts: u64) {
-    if task_ptr.is_null() || pkt.is_null() {
+pub extern "C" fn task_set_csum_policy(task_ptr: *mut Task, policy: ChecksumPolicy) {
+    if task_ptr.is_null() {
+        return;
+    }
+
+    let task = unsafe { &mut *task_ptr };
+    task.set_csum_policy(policy.into());
+}
+
+#[no_mangle]
+pub extern "C" fn task_set_timeout(task_ptr: *mut Task, timeout: u64) {
+    if task_ptr.is_null() {
+        return;
+    }
+
+    let task = unsafe { &mut *task_ptr };
+    task.set_timeout(timeout.into());
+}
+
+// 定期调用，空闲超时的流结束，之后可以用task_get_meta取出解析器最后产生的meta
+#[no_mangle]
+pub extern "C" fn task_timeout(task_ptr: *mut Task, now: u64) {
+    if task_ptr.is_null() {
         return;
     }
 
+    let task = unsafe { &mut *task_ptr };
+    task.timeout(now.into());
+}
+
+#[no_mangle]
+pub extern "C" fn task_conn_state(task_ptr: *const Task) -> TcpState {
+    if task_ptr.is_null() {
+        return TcpState::Init;
+    }
+
+    let task = unsafe { &*task_ptr };
+    task.conn_state().into()
+}
+
+// 没有见到握手，从连接中途开始时返回1
+#[no_mangle]
+pub extern "C" fn task_is_midstream(task_ptr: *const Task) -> c_int {
+    if task_ptr.is_null() {
+        return 0;
+    }
+
+    let task = unsafe { &*task_ptr };
+    task.is_midstream().into()
+}
+
+// 握手没有完成就被rst时返回1
+#[no_mangle]
+pub extern "C" fn task_is_rejected(task_ptr: *const Task) -> c_int {
+    if task_ptr.is_null() {
+        return 0;
+    }
+
+    let task = unsafe { &*task_ptr };
+    task.is_rejected().into()
+}
+
+// datalink是pcap_datalink返回的DLT值。err_offset可以为NULL
+#[no_mangle]
+pub extern "C" fn task_run(task_ptr: *mut Task, pkt: *const u8, pkt_len: usize, datalink: c_int, pkt_dir: PacketDir, ts: u64, err_offset: *mut usize) -> PktErrCode {
+    if task_ptr.is_null() || pkt.is_null() {
+        return PktErrCode::InvalidArg;
+    }
+    let link_type = match LinkType::from_dlt(datalink) {
+        Some(link_type) => link_type,
+        None => return PktErrCode::UnsupportedLink,
+    };
+
     let task = unsafe { &mut *task_ptr };     
     let data = unsafe { std::slice::from_raw_parts(pkt, pkt_len) };
-    let packet = Packet::new(ts.into(), pkt_len, data);
-    if packet.decode().is_err() {
-        return;
+    // data只在本次调用中有效，task.run缓存时只拷贝载荷，返回之后不再引用data
+    let packet = match unsafe { Packet::new_borrowed(ts.into(), pkt_len, data, link_type) } {
+        Ok(packet) => packet,
+        Err(err) => return pkt_err(err, err_offset),
+    };
+    if let Err(err) = packet.decode_with_csum(task.csum_policy()) {
+        return pkt_err(err, err_offset);
     }
 
     task.run(packet, pkt_dir.into());
+    PktErrCode::Ok
+}
+
+#[no_mangle]
+pub extern "C" fn flow_table_new(parser_type: ParserType) -> *mut FlowTable {
+    let flow_table = match parser_type {
+        ParserType::Smtp => FlowTable::new(|_| Some(Box::new(SmtpParser))),
+        _ => return ptr::null_mut(),
+    };
+    Box::into_raw(Box::new(flow_table))
+}
+
+#[no_mangle]
+pub extern "C" fn flow_table_free(ptr: *mut FlowTable) {
+    if ptr.is_null() {
+        return;
+    }
+
+    unsafe { let _ = Box::from_raw(ptr); }
+}
+
+#[no_mangle]
+pub extern "C" fn flow_table_set_csum_policy(ft_ptr: *mut FlowTable, policy: ChecksumPolicy) {
+    if ft_ptr.is_null() {
+        return;
+    }
+
+    let flow_table = unsafe { &mut *ft_ptr };
+    flow_table.set_csum_policy(policy.into());
+}
+
+#[no_mangle]
+pub extern "C" fn flow_table_set_strm_timeout(ft_ptr: *mut FlowTable, timeout: u64) {
+    if ft_ptr.is_null() {
+        return;
+    }
+
+    let flow_table = unsafe { &mut *ft_ptr };
+    flow_table.set_strm_timeout(timeout.into());
+}
+
+#[no_mangle]
+pub extern "C" fn flow_table_set_midstream_wait(ft_ptr: *mut FlowTable, wait: u64) {
+    if ft_ptr.is_null() {
+        return;
+    }
+
+    let flow_table = unsafe { &mut *ft_ptr };
+    flow_table.set_midstream_wait(wait.into());
+}
+
+#[no_mangle]
+pub extern "C" fn flow_table_set_cache_limit(ft_ptr: *mut FlowTable, max_pkts: usize, max_bytes: usize) {
+    if ft_ptr.is_null() {
+        return;
+    }
+
+    let flow_table = unsafe { &mut *ft_ptr };
+    flow_table.set_cache_limit(max_pkts, max_bytes);
+}
+
+// 之后新建的连接共用limit字节的缓存
+#[no_mangle]
+pub extern "C" fn flow_table_set_mem_budget(ft_ptr: *mut FlowTable, limit: usize) {
+    if ft_ptr.is_null() {
+        return;
+    }
+
+    let flow_table = unsafe { &mut *ft_ptr };
+    flow_table.set_mem_budget(limit);
+}
+
+#[no_mangle]
+pub extern "C" fn flow_table_mem_used(ft_ptr: *const FlowTable) -> usize {
+    if ft_ptr.is_null() {
+        return 0;
+    }
+
+    let flow_table = unsafe { &*ft_ptr };
+    flow_table.mem_budget().map_or(0, |budget| budget.used())
+}
+
+// 因为连接数达到上限而被忽略的包数
+#[no_mangle]
+pub extern "C" fn flow_table_full_drops(ft_ptr: *const FlowTable) -> u64 {
+    if ft_ptr.is_null() {
+        return 0;
+    }
+
+    let flow_table = unsafe { &*ft_ptr };
+    flow_table.full_drops()
+}
+
+#[no_mangle]
+pub extern "C" fn flow_table_set_timeout(ft_ptr: *mut FlowTable, timeout: u64) {
+    if ft_ptr.is_null() {
+        return;
+    }
+
+    let flow_table = unsafe { &mut *ft_ptr };
+    flow_table.set_timeout(timeout.into());
+}
+
+// 不需要区分方向，没有解码的包直接传进来。err_offset同task_run
+#[no_mangle]
+pub extern "C" fn flow_table_run(ft_ptr: *mut FlowTable, pkt: *const u8, pkt_len: usize, datalink: c_int, ts: u64, err_offset: *mut usize) -> PktErrCode {
+    if ft_ptr.is_null() || pkt.is_null() {
+        return PktErrCode::InvalidArg;
+    }
+    let link_type = match LinkType::from_dlt(datalink) {
+        Some(link_type) => link_type,
+        None => return PktErrCode::UnsupportedLink,
+    };
+
+    let flow_table = unsafe { &mut *ft_ptr };
+    let data = unsafe { std::slice::from_raw_parts(pkt, pkt_len) };
+    let packet = match unsafe { Packet::new_borrowed(ts.into(), pkt_len, data, link_type) } {
+        Ok(packet) => packet,
+        Err(err) => return pkt_err(err, err_offset),
+    };
+    match flow_table.run(packet) {
+        Ok(_) => PktErrCode::Ok,
+        Err(err) => pkt_err(err, err_offset),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn flow_table_timeout(ft_ptr: *mut FlowTable, now: u64) {
+    if ft_ptr.is_null() {
+        return;
+    }
+
+    let flow_table = unsafe { &mut *ft_ptr };
+    flow_table.timeout(now.into());
+}
+
+#[no_mangle]
+pub extern "C" fn flow_table_len(ft_ptr: *const FlowTable) -> usize {
+    if ft_ptr.is_null() {
+        return 0;
+    }
+
+    let flow_table = unsafe { &*ft_ptr };
+    flow_table.len()
+}
+
+#[no_mangle]
+pub extern "C" fn flow_table_get_meta(ft_ptr: *mut FlowTable) -> *const Meta {
+    if ft_ptr.is_null() {
+        return ptr::null_mut();
+    }
+
+    let flow_table = unsafe { &mut *ft_ptr };
+    if let Some((_, meta)) = flow_table.get_meta() {
+        return Box::into_raw(Box::new(meta));
+    }
+    ptr::null_mut()
 }
 
 #[no_mangle]
-pub extern fn task_get_meta(task_ptr: *mut Task) -> *const Meta {
+pub extern "C" fn task_get_meta(task_ptr: *mut Task) -> *const Meta {
     if task_ptr.is_null() {
         return ptr::null_mut();
     }
@@ -103,7 +419,7 @@ pub extern fn task_get_meta(task_ptr: *mut Task) -> *const Meta {
 }
 
 #[no_mangle]
-pub extern fn meta_free(meta_ptr: *mut Meta) {
+pub extern "C" fn meta_free(meta_ptr: *mut Meta) {
     if meta_ptr.is_null() {
         return;
     }
@@ -112,7 +428,7 @@ pub extern fn meta_free(meta_ptr: *mut Meta) {
 }
 
 #[no_mangle]
-pub extern fn meta_protocol(meta_ptr: *mut Meta) -> ParserType {
+pub extern "C" fn meta_protocol(meta_ptr: *mut Meta) -> ParserType {
     if meta_ptr.is_null() {
         return ParserType::Undef;
     }
@@ -139,7 +455,7 @@ pub enum MetaSmtpType {
 }
 
 #[no_mangle]
-pub extern fn smtp_meta_type(meta_ptr: *mut Meta) -> MetaSmtpType {
+pub extern "C" fn smtp_meta_type(meta_ptr: *mut Meta) -> MetaSmtpType {
     if meta_ptr.is_null() {
         return MetaSmtpType::None;
     }
@@ -160,7 +476,7 @@ pub extern fn smtp_meta_type(meta_ptr: *mut Meta) -> MetaSmtpType {
 }
 
 #[no_mangle]
-pub extern fn smtp_meta_user(meta_ptr: *mut Meta) -> *const c_char{
+pub extern "C" fn smtp_meta_user(meta_ptr: *mut Meta) -> *const c_char{
     if meta_ptr.is_null() {
         return ptr::null();
     }
@@ -174,7 +490,7 @@ pub extern fn smtp_meta_user(meta_ptr: *mut Meta) -> *const c_char{
 }
 
 #[no_mangle]
-pub extern fn smtp_meta_user_free(user: *mut c_char) {
+pub extern "C" fn smtp_meta_user_free(user: *mut c_char) {
     if user.is_null() {
         return;
     }
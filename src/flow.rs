@@ -0,0 +1,148 @@
+use etherparse::{VlanHeader, TransportHeader};
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hash, Hasher};
+use std::net::IpAddr;
+use crate::{Packet, PktHeader};
+
+const IP_TCP: u8 = 6;
+const IP_UDP: u8 = 17;
+
+// 1024以上常见的服务端口
+const SERVER_PORTS: [u16; 12] = [1433, 1521, 2049, 3306, 3389, 5432, 5672, 6379, 8080, 8443, 9200, 27017];
+
+// 标识一个连接。vlan和tunnel_id用来区分地址重叠的不同网络
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    pub saddr: IpAddr,
+    pub daddr: IpAddr,
+    pub sport: u16,
+    pub dport: u16,
+    pub proto: u8,
+    pub vlan: Option<u16>,      // 最内层的vlan id
+    pub tunnel_id: Option<u32>, // 最内层隧道的id
+}
+
+impl FlowKey {
+    // 包需要已经解码，且是tcp或者udp
+    pub fn from_pkt(pkt: &Packet) -> Option<FlowKey> {
+        let header = pkt.header.borrow();
+        FlowKey::from_header(header.as_ref()?)
+    }
+
+    pub fn from_header(header: &PktHeader) -> Option<FlowKey> {
+        let proto = match header.transport.as_ref()? {
+            TransportHeader::Tcp(_) => IP_TCP,
+            TransportHeader::Udp(_) => IP_UDP,
+            _ => return None,
+        };
+        // 内层没有vlan时，用隧道外层的
+        let vlan = std::iter::once(&header.vlan)
+            .chain(header.tunnels.iter().rev().map(|tunnel| &tunnel.vlan))
+            .find_map(|vlan| vlan.as_ref().map(vlan_id));
+
+        Some(FlowKey {
+            saddr: header.saddr()?,
+            daddr: header.daddr()?,
+            sport: header.sport(),
+            dport: header.dport(),
+            proto,
+            vlan,
+            tunnel_id: header.tunnel_id(),
+        })
+    }
+
+    pub fn reverse(&self) -> FlowKey {
+        FlowKey {
+            saddr: self.daddr,
+            daddr: self.saddr,
+            sport: self.dport,
+            dport: self.sport,
+            ..*self
+        }
+    }
+
+    // (地址, 端口)小的一端作为源，两个方向的包得到同一个key
+    pub fn canonical(&self) -> FlowKey {
+        if self.is_canonical() {
+            *self
+        } else {
+            self.reverse()
+        }
+    }
+
+    pub fn is_canonical(&self) -> bool {
+        (self.saddr, self.sport) <= (self.daddr, self.dport)
+    }
+
+    // 和方向无关的hash值，可以用来把连接分到不同的线程
+    pub fn flow_hash(&self) -> u64 {
+        let mut hasher = FlowHasher::default();
+        self.canonical().hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+// 熟知端口和常见的服务端口，用来在没有握手时猜测哪一端是服务器
+pub fn is_server_port(port: u16) -> bool {
+    port < 1024 || SERVER_PORTS.contains(&port)
+}
+
+fn vlan_id(vlan: &VlanHeader) -> u16 {
+    match vlan {
+        VlanHeader::Single(vlan) => vlan.vlan_identifier,
+        VlanHeader::Double(vlan) => vlan.inner.vlan_identifier,
+    }
+}
+
+// FxHash。key都是本地解析出来的，不需要SipHash防碰撞攻击
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FlowHasher {
+    hash: u64,
+}
+
+const SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+
+impl FlowHasher {
+    fn add(&mut self, word: u64) {
+        self.hash = (self.hash.rotate_left(5) ^ word).wrapping_mul(SEED);
+    }
+}
+
+impl Hasher for FlowHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        let mut chunks = bytes.chunks_exact(8);
+        for chunk in &mut chunks {
+            self.add(u64::from_le_bytes(chunk.try_into().unwrap()));
+        }
+        for byte in chunks.remainder() {
+            self.add(*byte as u64);
+        }
+    }
+
+    fn write_u8(&mut self, i: u8) {
+        self.add(i as u64);
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.add(i as u64);
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.add(i as u64);
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.add(i);
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.add(i as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        self.hash
+    }
+}
+
+pub type FlowBuildHasher = BuildHasherDefault<FlowHasher>;
+pub type FlowMap<V> = HashMap<FlowKey, V, FlowBuildHasher>;
@@ -0,0 +1,242 @@
+use std::collections::VecDeque;
+use std::rc::Rc;
+use crate::{DEFAULT_STRM_TIMEOUT, DEFAULT_MAX_PKTS, DEFAULT_MAX_BYTES, MemBudget, Anomaly, ConnState, CsumPolicy, OverlapPolicy, Defrag, FlowKey, FlowMap, Meta, Packet, PacketError, Parser, PktDirection, Task};
+
+pub const DEFAULT_FLOW_TIMEOUT: u128 = 300_000; // 毫秒
+const CLOSED_LINGER: u128 = 10_000; // 毫秒
+const DEFAULT_MAX_FLOWS: usize = 65536;
+const IP_TCP: u8 = 6;
+
+// 根据连接创建解析器，返回None表示不关心这个连接
+pub type ParserFactory = Box<dyn FnMut(&FlowKey) -> Option<Box<dyn Parser>>>;
+
+struct Flow {
+    task: Task,
+    last_ts: u128,
+}
+
+// 按连接管理Task。输入没有解码的包，分片重组，解码，找到或者创建Task。
+// 两个方向都结束或者收到有效rst，并且解析器都已经结束的连接立即删除，空闲超时的连接由timeout删除。
+// 刚删除的连接在CLOSED_LINGER内，不是syn的包(挥手最后的ack，重传)不会重新建立连接
+pub struct FlowTable {
+    flows: FlowMap<Flow>,
+    closed: FlowMap<u128>, // 刚删除的连接和删除的时间
+    factory: ParserFactory,
+    defrag: Defrag,
+    metas: VecDeque<(FlowKey, Meta)>,
+    anomalies: VecDeque<(FlowKey, PktDirection, Anomaly)>,
+    timeout: u128,
+    strm_timeout: u128,
+    midstream_wait: u128,
+    max_flows: usize,
+    full_drops: u64, // 连接数达到上限时被忽略的包
+    csum_policy: CsumPolicy,
+    overlap_policy: OverlapPolicy,
+    cache_limit: (usize, usize),
+    budget: Option<Rc<MemBudget>>,
+}
+
+impl FlowTable {
+    pub fn new(factory: impl FnMut(&FlowKey) -> Option<Box<dyn Parser>> + 'static) -> Self {
+        FlowTable {
+            flows: FlowMap::default(),
+            closed: FlowMap::default(),
+            factory: Box::new(factory),
+            defrag: Defrag::new(),
+            metas: VecDeque::new(),
+            anomalies: VecDeque::new(),
+            timeout: DEFAULT_FLOW_TIMEOUT,
+            strm_timeout: DEFAULT_STRM_TIMEOUT,
+            midstream_wait: 0,
+            max_flows: DEFAULT_MAX_FLOWS,
+            full_drops: 0,
+            csum_policy: CsumPolicy::default(),
+            overlap_policy: OverlapPolicy::default(),
+            cache_limit: (DEFAULT_MAX_PKTS, DEFAULT_MAX_BYTES),
+            budget: None,
+        }
+    }
+
+    pub fn set_timeout(&mut self, timeout: u128) {
+        self.timeout = timeout;
+    }
+
+    // 新建的task中流的空闲超时，见Task::set_timeout
+    pub fn set_strm_timeout(&mut self, timeout: u128) {
+        self.strm_timeout = timeout;
+    }
+
+    // 新建的task中流的中途接入等待时间，见PktStrm::set_midstream_wait
+    pub fn set_midstream_wait(&mut self, wait: u128) {
+        self.midstream_wait = wait;
+    }
+
+    // 连接数达到上限后，新的连接被忽略，由full_drops计数
+    pub fn set_max_flows(&mut self, max_flows: usize) {
+        self.max_flows = max_flows;
+    }
+
+    // 因为连接数达到上限而被忽略的包数
+    pub fn full_drops(&self) -> u64 {
+        self.full_drops
+    }
+
+    pub fn set_csum_policy(&mut self, policy: CsumPolicy) {
+        self.csum_policy = policy;
+    }
+
+    pub fn set_overlap_policy(&mut self, policy: OverlapPolicy) {
+        self.overlap_policy = policy;
+    }
+
+    // 新建的task中每个流的缓存限制，见Task::set_cache_limit
+    pub fn set_cache_limit(&mut self, max_pkts: usize, max_bytes: usize) {
+        self.cache_limit = (max_pkts, max_bytes);
+    }
+
+    // 之后新建的连接共用limit字节的缓存
+    pub fn set_mem_budget(&mut self, limit: usize) {
+        self.budget = Some(Rc::new(MemBudget::new(limit)));
+    }
+
+    pub fn mem_budget(&self) -> Option<&MemBudget> {
+        self.budget.as_deref()
+    }
+
+    pub fn defrag_mut(&mut self) -> &mut Defrag {
+        &mut self.defrag
+    }
+
+    pub fn len(&self) -> usize {
+        self.flows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.flows.clear();
+        self.closed.clear();
+        self.defrag.clear();
+    }
+
+    // 处理一个包，返回所属连接的key(客户端到服务器方向)。
+    // 分片没有收齐，不是tcp，或者工厂不关心的连接，返回Ok(None)
+    pub fn run(&mut self, pkt: Rc<Packet>) -> Result<Option<FlowKey>, PacketError> {
+        let pkt = match self.defrag.push(pkt) {
+            Some(pkt) => pkt,
+            None => return Ok(None),
+        };
+        pkt.decode_with_csum(self.csum_policy)?;
+        let key = match FlowKey::from_pkt(&pkt) {
+            Some(key) if key.proto == IP_TCP => key,
+            _ => return Ok(None),
+        };
+
+        let canonical = key.canonical();
+        if !self.flows.contains_key(&canonical) {
+            if let Some(&ts) = self.closed.get(&canonical) {
+                if !pkt.syn() && pkt.timestamp.saturating_sub(ts) <= CLOSED_LINGER {
+                    return Ok(None);
+                }
+                self.closed.remove(&canonical);
+            }
+            if self.flows.len() >= self.max_flows {
+                self.full_drops += 1;
+                return Ok(None);
+            }
+            let parser = match (self.factory)(&key) {
+                Some(parser) => parser,
+                None => return Ok(None),
+            };
+            let mut task = Task::new_with_parser(parser);
+            task.set_csum_policy(self.csum_policy);
+            task.set_timeout(self.strm_timeout);
+            task.set_midstream_wait(self.midstream_wait);
+            task.set_overlap_policy(self.overlap_policy);
+            task.set_cache_limit(self.cache_limit.0, self.cache_limit.1);
+            if let Some(budget) = &self.budget {
+                task.set_budget(Rc::clone(budget));
+            }
+            self.flows.insert(canonical, Flow { task, last_ts: pkt.timestamp });
+        }
+
+        // 方向由task自己判断
+        let flow = self.flows.get_mut(&canonical).unwrap();
+        flow.last_ts = flow.last_ts.max(pkt.timestamp);
+        flow.task.run(pkt, PktDirection::Unknown);
+        let client = flow.task.client().unwrap_or(key);
+        while let Some(meta) = flow.task.get_meta() {
+            self.metas.push_back((client, meta));
+        }
+        while let Some((dir, anomaly)) = flow.task.get_anomaly() {
+            self.anomalies.push_back((client, dir, anomaly));
+        }
+
+        // fin先于乱序的数据到达，或者解析器还阻塞在缺失上时，等数据到达或者流超时
+        if is_closed(&flow.task) {
+            let last_ts = flow.last_ts;
+            self.flows.remove(&canonical);
+            self.closed.insert(canonical, last_ts);
+        }
+        Ok(Some(client))
+    }
+
+    // 所有连接产生的meta，按产生的顺序
+    pub fn get_meta(&mut self) -> Option<(FlowKey, Meta)> {
+        self.metas.pop_front()
+    }
+
+    // 所有连接中发现的异常，按发现的顺序
+    pub fn get_anomaly(&mut self) -> Option<(FlowKey, PktDirection, Anomaly)> {
+        self.anomalies.pop_front()
+    }
+
+    pub fn task(&self, key: &FlowKey) -> Option<&Task> {
+        self.flows.get(&key.canonical()).map(|flow| &flow.task)
+    }
+
+    pub fn task_mut(&mut self, key: &FlowKey) -> Option<&mut Task> {
+        self.flows.get_mut(&key.canonical()).map(|flow| &mut flow.task)
+    }
+
+    pub fn remove(&mut self, key: &FlowKey) -> Option<Task> {
+        self.flows.remove(&key.canonical()).map(|flow| flow.task)
+    }
+
+    // 流超时的连接先让解析器结束，收集产生的meta，再删除已经关闭或者空闲超时的连接和分片。
+    // now和Packet::timestamp单位相同
+    pub fn timeout(&mut self, now: u128) {
+        let timeout = self.timeout;
+        let metas = &mut self.metas;
+        for flow in self.flows.values_mut() {
+            // 空闲的连接删除之前结束两个方向的流，收集解析器最后的meta
+            if now.saturating_sub(flow.last_ts) > timeout {
+                flow.task.expire();
+            } else if !flow.task.timeout(now) {
+                continue;
+            }
+            if let Some(client) = flow.task.client() {
+                while let Some(meta) = flow.task.get_meta() {
+                    metas.push_back((client, meta));
+                }
+            }
+        }
+        let closed = &mut self.closed;
+        self.flows.retain(|key, flow| {
+            if is_closed(&flow.task) {
+                closed.insert(*key, now);
+                return false;
+            }
+            now.saturating_sub(flow.last_ts) <= timeout
+        });
+        self.closed.retain(|_, ts| now.saturating_sub(*ts) <= CLOSED_LINGER);
+        self.defrag.timeout(now);
+    }
+}
+
+// 连接已经关闭，解析器也读完了流中的数据
+fn is_closed(task: &Task) -> bool {
+    matches!(task.conn_state(), ConnState::Closed | ConnState::Reset) && task.is_finished()
+}
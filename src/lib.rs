@@ -4,11 +4,21 @@ mod util;
 mod task;
 mod parser;
 mod ffi;
+mod defrag;
+mod tunnel;
+mod flow;
+mod flowtable;
+mod conn;
 
 pub use util::*;
 pub use packet::*;
 pub use pktstrm::*;
 pub use task::*;
 pub use parser::*;
+pub use defrag::*;
+pub use tunnel::*;
+pub use flow::*;
+pub use flowtable::*;
+pub use conn::*;
 
 
@@ -1,12 +1,111 @@
-use etherparse::{PacketHeaders, Ethernet2Header, VlanHeader, IpHeader, TransportHeader};
+use etherparse::{Ethernet2Header, VlanHeader, IpHeader, TransportHeader, TcpHeader, TcpOptionElement, SerializedSize, ether_type};
 use std::cell::RefCell;
 use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::ops::Deref;
 use std::rc::Rc;
+use crate::tunnel::{Layer, decap};
+use crate::{Tunnel, MAX_TUNNEL_DEPTH};
 
-pub const MAX_PACKET_LEN: usize = 2048;
+// 只用来拦截异常的caplen。GRO/TSO和巨帧都在这个范围内
+pub const MAX_PACKET_LEN: usize = 512 * 1024;
 
-#[derive(Eq, PartialEq, Clone)]
+// 链路层类型，决定decode从哪里开始解析
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LinkType {
+    #[default]
+    Ethernet,
+    LinuxSll,  // tcpdump -i any
+    LinuxSll2,
+    Null,      // BSD loopback，4字节的协议族
+    Raw,       // 直接是ip头
+}
+
+impl LinkType {
+    // pcap_datalink返回的DLT值
+    pub fn from_dlt(dlt: i32) -> Option<LinkType> {
+        match dlt {
+            1 => Some(LinkType::Ethernet),
+            113 => Some(LinkType::LinuxSll),
+            276 => Some(LinkType::LinuxSll2),
+            0 | 108 => Some(LinkType::Null), // DLT_NULL, DLT_LOOP
+            12 | 14 | 101 => Some(LinkType::Raw), // DLT_RAW在不同系统上的值，以及LINKTYPE_RAW
+            _ => None,
+        }
+    }
+
+    // 链路层头的最小长度
+    pub(crate) fn header_len(&self) -> usize {
+        match self {
+            LinkType::Ethernet => Ethernet2Header::SERIALIZED_SIZE,
+            LinkType::LinuxSll => 16,
+            LinkType::LinuxSll2 => 20,
+            LinkType::Null => 4,
+            LinkType::Raw => 1,
+        }
+    }
+
+    // 返回链路层之后的位置和ether type。以太网不跳过vlan
+    pub(crate) fn network(&self, data: &[u8]) -> Option<(usize, u16)> {
+        match self {
+            LinkType::Ethernet => {
+                let (eth, _) = Ethernet2Header::from_slice(data).ok()?;
+                Some((Ethernet2Header::SERIALIZED_SIZE, eth.ether_type))
+            }
+            LinkType::LinuxSll => {
+                let proto = data.get(14..16)?;
+                Some((16, u16::from_be_bytes([proto[0], proto[1]])))
+            }
+            LinkType::LinuxSll2 => {
+                data.get(..20)?;
+                let proto = data.get(0..2)?;
+                Some((20, u16::from_be_bytes([proto[0], proto[1]])))
+            }
+            LinkType::Null => {
+                // 协议族是抓包机器的字节序，DLT_LOOP是网络字节序，两种都试
+                let family = data.get(0..4)?;
+                let family = if family[0] != 0 {
+                    u32::from_le_bytes([family[0], family[1], family[2], family[3]])
+                } else {
+                    u32::from_be_bytes([family[0], family[1], family[2], family[3]])
+                };
+                match family {
+                    2 => Some((4, ether_type::IPV4)),
+                    10 | 24 | 28 | 30 => Some((4, ether_type::IPV6)), // linux, 各个BSD, macos
+                    _ => None,
+                }
+            }
+            LinkType::Raw => {
+                match data.first()? >> 4 {
+                    4 => Some((0, ether_type::IPV4)),
+                    6 => Some((0, ether_type::IPV6)),
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+
+// 校验和错误的包怎么处理
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CsumPolicy {
+    #[default]
+    Off,    // 不校验
+    Accept, // 校验并记录结果，错误的包照常使用。抓包的网卡有checksum offload时用
+    Flag,   // 错误的包标记为Flagged，PktStrm不用它重组
+    Drop,   // 错误的包返回PacketError::BadCsum
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CsumState {
+    #[default]
+    Unchecked, // 没有校验，或者包被截断无法校验
+    Good,
+    Bad,
+    Flagged,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
 pub enum PktDirection {
     Client2Server,
     Server2Client,
@@ -21,7 +120,9 @@ pub struct PktHeader {
     pub ip: Option<IpHeader>,
     pub transport: Option<TransportHeader>,
     pub payload_offset: usize,
-    pub payload_len: usize
+    pub payload_len: usize,
+    pub tunnels: Vec<Tunnel>, // 外层的隧道，从外到内
+    pub csum: CsumState,      // 内层ip和传输层的校验和
 }
 
 impl PktHeader {
@@ -42,77 +143,330 @@ impl PktHeader {
             _ => 0
         }
     }
-    
+
+    // 返回源ip地址，ipv4或者ipv6
+    pub fn saddr(&self) -> Option<IpAddr> {
+        match &self.ip {
+            Some(IpHeader::Version4(ipv4h, _)) => Some(IpAddr::V4(Ipv4Addr::from(ipv4h.source))),
+            Some(IpHeader::Version6(ipv6h, _)) => Some(IpAddr::V6(Ipv6Addr::from(ipv6h.source))),
+            None => None
+        }
+    }
+
+    // 返回目的ip地址，ipv4或者ipv6
+    pub fn daddr(&self) -> Option<IpAddr> {
+        match &self.ip {
+            Some(IpHeader::Version4(ipv4h, _)) => Some(IpAddr::V4(Ipv4Addr::from(ipv4h.destination))),
+            Some(IpHeader::Version6(ipv6h, _)) => Some(IpAddr::V6(Ipv6Addr::from(ipv6h.destination))),
+            None => None
+        }
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        matches!(self.ip, Some(IpHeader::Version6(_, _)))
+    }
+
+    // 最内层隧道的id，没有隧道或者隧道没有id返回None
+    pub fn tunnel_id(&self) -> Option<u32> {
+        self.tunnels.last().and_then(|tunnel| tunnel.id)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DataState {
+    Owned,    // 自己持有整个包
+    Borrowed, // 引用调用者的内存
+    Detached, // 借用的包被缓存时拷贝出来的，只有载荷
 }
 
-#[derive(Eq, PartialEq, Clone)]
 pub struct Packet {
     pub timestamp: u128,
-    pub data: [u8; MAX_PACKET_LEN],
-    pub data_len: usize,
-    pub header: RefCell<Option<PktHeader>>
+    pub header: RefCell<Option<PktHeader>>,
+    link_type: LinkType,
+    // owned为None时引用调用者的内存。创建之后不再改变，取出的slice在包的生命周期内一直有效
+    ptr: *const u8,
+    len: usize,
+    owned: Option<Box<[u8]>>,
+    state: DataState,
 }
 
 impl Packet {
-    pub fn new(ts: u128, len: usize, data: &[u8]) -> Rc<Packet> {
-        let mut pkt = Packet {
+    // 按实际长度分配，只拷贝len个字节
+    pub fn new(ts: u128, len: usize, data: &[u8]) -> Result<Rc<Packet>, PacketError> {
+        Packet::new_with_link(ts, len, data, LinkType::Ethernet)
+    }
+
+    pub fn new_with_link(ts: u128, len: usize, data: &[u8], link_type: LinkType) -> Result<Rc<Packet>, PacketError> {
+        if len > MAX_PACKET_LEN {
+            return Err(PacketError::Oversize { offset: MAX_PACKET_LEN });
+        }
+        if len > data.len() {
+            return Err(PacketError::Truncated { offset: data.len() });
+        }
+
+        Ok(Rc::new(Packet::from_owned(ts, link_type, data[..len].into(), None, DataState::Owned)))
+    }
+
+    /// 零拷贝，直接引用调用者的内存，比如AF_PACKET或者DPDK的ring buffer。
+    /// Task只在本次run中使用这个包，需要缓存时拷贝出只有载荷的包，解析器拿到的都是拷贝。
+    ///
+    /// # Safety
+    /// 在包被释放之前，data必须一直有效且不被修改。
+    /// 调用者在Task::run之后不再持有这个包时，只需要保证data在Task::run期间有效
+    pub unsafe fn new_borrowed(ts: u128, len: usize, data: &[u8], link_type: LinkType) -> Result<Rc<Packet>, PacketError> {
+        if len > MAX_PACKET_LEN {
+            return Err(PacketError::Oversize { offset: MAX_PACKET_LEN });
+        }
+        if len > data.len() {
+            return Err(PacketError::Truncated { offset: data.len() });
+        }
+
+        Ok(Rc::new(Packet {
             timestamp: ts,
-            data_len: len,
-            data: [0; MAX_PACKET_LEN],
-            header: RefCell::new(None)
+            header: RefCell::new(None),
+            link_type,
+            ptr: data.as_ptr(),
+            len,
+            owned: None,
+            state: DataState::Borrowed,
+        }))
+    }
+
+    fn from_owned(ts: u128, link_type: LinkType, data: Box<[u8]>, header: Option<PktHeader>, state: DataState) -> Packet {
+        Packet {
+            timestamp: ts,
+            header: RefCell::new(header),
+            link_type,
+            ptr: data.as_ptr(),
+            len: data.len(),
+            owned: Some(data),
+            state,
+        }
+    }
+
+    // 缓存中借用的包的拷贝只有载荷，payload_offset为0，头部不再可用
+    pub fn data(&self) -> &[u8] {
+        match &self.owned {
+            Some(data) => data,
+            // 调用者保证借用的内存在包的生命周期内有效
+            None => unsafe { std::slice::from_raw_parts(self.ptr, self.len) },
+        }
+    }
+
+    pub fn link_type(&self) -> LinkType {
+        self.link_type
+    }
+
+    pub fn data_len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_borrowed(&self) -> bool {
+        self.state == DataState::Borrowed
+    }
+
+    // 拷贝出自己持有数据的包，不改变原来的包。已经解码的只拷贝载荷，payload_offset为0，之后不能再decode
+    pub(crate) fn detached(&self) -> Rc<Packet> {
+        let mut header = self.header.borrow().clone();
+        let (data, state): (Box<[u8]>, _) = match header.as_mut() {
+            Some(header) => {
+                let payload = &self.data()[header.payload_offset..header.payload_offset + header.payload_len];
+                header.payload_offset = 0;
+                (payload.into(), DataState::Detached)
+            }
+            None => (self.data().into(), DataState::Owned),
         };
-        let s_data = &mut pkt.data[..len];
-        s_data.copy_from_slice(&data[..len]);
-        Rc::new(pkt)
+        Rc::new(Packet::from_owned(self.timestamp, self.link_type, data, header, state))
     }
 
     pub fn decode(&self) -> Result<(), PacketError> {
-        match PacketHeaders::from_ethernet_slice(self) {
-            Ok(headers) => {
-                if headers.ip.is_none() || headers.transport.is_none()  {
-                    return Err(PacketError::DecodeErr);
-                } 
-                
-                self.header.replace(Some(PktHeader {
-                    link: headers.link,
-                    vlan: headers.vlan,
-                    ip: headers.ip,
-                    transport: headers.transport,
-                    payload_offset: headers.payload.as_ptr() as usize - self.data.as_ptr() as usize,
-                    payload_len: self.data_len - (headers.payload.as_ptr() as usize - self.data.as_ptr() as usize)
-                }));
-                Ok(())
+        if self.state == DataState::Detached {
+            return Ok(());
+        }
+
+        // 逐层解析，遇到隧道就记下外层，继续解内层
+        let mut layer = Layer::Link(self.link_type);
+        let mut data = self.data();
+        let mut base = 0;
+        let mut tunnels = Vec::new();
+        let headers = loop {
+            let headers = layer.decode(data, base)?;
+            match decap(&headers) {
+                Some((tunnel, offset, next)) => {
+                    if tunnels.len() >= MAX_TUNNEL_DEPTH {
+                        return Err(PacketError::TunnelDepth { offset: headers.offset });
+                    }
+                    tunnels.push(tunnel);
+                    data = headers.payload.get(offset..).ok_or(PacketError::Truncated { offset: self.data_len() })?;
+                    base = headers.offset + offset;
+                    layer = next;
+                }
+                None => break headers,
+            }
+        };
+
+        let offset = headers.offset;
+        let (ip, transport) = match (&headers.ip, &headers.transport) {
+            (Some(ip), Some(transport)) => (ip, transport),
+            (Some(_), None) if headers.fragment => return Err(PacketError::Fragmented { offset }),
+            (Some(ip), None) => {
+                let proto = ip.next_header().unwrap_or(0);
+                return Err(PacketError::NonTcpUdp { offset, proto });
             }
-            Err(_) => Err(PacketError::DecodeErr),
+            // 802.3的长度字段不是ether type
+            (None, _) if headers.ether < 0x0600 => return Err(PacketError::NonIp { offset }),
+            (None, _) => return Err(PacketError::UnsupportedEtherType { offset, ether_type: headers.ether }),
+        };
+
+        let payload_offset = offset;
+        let mut payload_len = self.data_len().saturating_sub(payload_offset);
+        if let Some(len) = ip_payload_len(ip, transport) {
+            payload_len = payload_len.min(len);
+        }
+
+        self.header.replace(Some(PktHeader {
+            link: headers.link,
+            vlan: headers.vlan,
+            ip: headers.ip,
+            transport: headers.transport,
+            payload_offset,
+            payload_len,
+            tunnels,
+            csum: CsumState::Unchecked,
+        }));
+        Ok(())
+    }
+
+    pub fn decode_with_csum(&self, policy: CsumPolicy) -> Result<(), PacketError> {
+        self.decode()?;
+        self.check_csum(policy)
+    }
+
+    // 已经解码的包按策略校验。已经校验过的不再重算，只按新的策略处理结果
+    pub fn check_csum(&self, policy: CsumPolicy) -> Result<(), PacketError> {
+        if policy == CsumPolicy::Off || self.state == DataState::Detached {
+            return Ok(());
+        }
+
+        let mut header = self.header.borrow_mut();
+        let header = match header.as_mut() {
+            Some(header) => header,
+            None => return Ok(()),
+        };
+        let bad = match header.csum {
+            CsumState::Good => None,
+            CsumState::Bad | CsumState::Flagged => Some(csum_offset(header)),
+            CsumState::Unchecked => match csum_check(header, self.data()) {
+                Some(bad) => bad,
+                None => return Ok(()),
+            },
+        };
+
+        header.csum = match (bad, policy) {
+            (None, _) => CsumState::Good,
+            (Some(_), CsumPolicy::Flag) => CsumState::Flagged,
+            (Some(_), _) => CsumState::Bad,
+        };
+        match (bad, policy) {
+            (Some(offset), CsumPolicy::Drop) => Err(PacketError::BadCsum { offset }),
+            _ => Ok(()),
         }
     }
     
+    // 以下的tcp字段，没有解码或者不是tcp时，seq等返回0和false，其他返回None
     pub fn seq(&self) -> u32 {
-        if let Some(TransportHeader::Tcp(tcph)) = &self.header.borrow().as_ref().unwrap().transport {
-            tcph.sequence_number            
-        } else {
-            0
-        }
+        self.tcp(|tcph| tcph.sequence_number).unwrap_or(0)
     }
 
     pub fn syn(&self) -> bool {
-        if let Some(TransportHeader::Tcp(tcph)) = &self.header.borrow().as_ref().unwrap().transport {
-            tcph.syn
-        } else {
-            false
-        }
+        self.tcp(|tcph| tcph.syn).unwrap_or(false)
     }
     
     pub fn fin(&self) -> bool {
-        if let Some(TransportHeader::Tcp(tcph)) = &self.header.borrow().as_ref().unwrap().transport {
-            tcph.fin
-        } else {
-            false
-        }
+        self.tcp(|tcph| tcph.fin).unwrap_or(false)
     }
     
     pub fn payload_len(&self) -> u32 {
-        self.header.borrow().as_ref().unwrap().payload_len.try_into().unwrap()
+        self.header.borrow().as_ref().map_or(0, |header| header.payload_len as u32)
+    }
+
+    // 只有ack标志置位时才有意义
+    pub fn ack_seq(&self) -> Option<u32> {
+        self.tcp(|tcph| tcph.ack.then_some(tcph.acknowledgment_number)).flatten()
+    }
+
+    pub fn ack(&self) -> Option<bool> {
+        self.tcp(|tcph| tcph.ack)
+    }
+
+    pub fn rst(&self) -> Option<bool> {
+        self.tcp(|tcph| tcph.rst)
+    }
+
+    pub fn psh(&self) -> Option<bool> {
+        self.tcp(|tcph| tcph.psh)
+    }
+
+    pub fn urg(&self) -> Option<bool> {
+        self.tcp(|tcph| tcph.urg)
+    }
+
+    // 原始的窗口值，没有乘window scale
+    pub fn window(&self) -> Option<u16> {
+        self.tcp(|tcph| tcph.window_size)
+    }
+
+    pub fn mss(&self) -> Option<u16> {
+        self.tcp_option(|opt| match opt {
+            TcpOptionElement::MaximumSegmentSize(mss) => Some(*mss),
+            _ => None,
+        })
+    }
+
+    pub fn wscale(&self) -> Option<u8> {
+        self.tcp_option(|opt| match opt {
+            TcpOptionElement::WindowScale(wscale) => Some(*wscale),
+            _ => None,
+        })
+    }
+
+    pub fn sack_permitted(&self) -> bool {
+        self.tcp_option(|opt| match opt {
+            TcpOptionElement::SelectiveAcknowledgementPermitted => Some(()),
+            _ => None,
+        }).is_some()
+    }
+
+    // 每一块是(左边界, 右边界)
+    pub fn sack_blocks(&self) -> Option<Vec<(u32, u32)>> {
+        self.tcp_option(|opt| match opt {
+            TcpOptionElement::SelectiveAcknowledgement(first, rest) => {
+                Some(std::iter::once(*first).chain(rest.iter().flatten().copied()).collect())
+            }
+            _ => None,
+        })
+    }
+
+    // (TSval, TSecr)
+    pub fn tcp_ts(&self) -> Option<(u32, u32)> {
+        self.tcp_option(|opt| match opt {
+            TcpOptionElement::Timestamp(val, ecr) => Some((*val, *ecr)),
+            _ => None,
+        })
+    }
+
+    fn tcp<R>(&self, f: impl FnOnce(&TcpHeader) -> R) -> Option<R> {
+        match self.header.borrow().as_ref()?.transport.as_ref()? {
+            TransportHeader::Tcp(tcph) => Some(f(tcph)),
+            _ => None,
+        }
+    }
+
+    // 按顺序找第一个匹配的选项，遇到格式错误的选项就停止
+    fn tcp_option<R>(&self, mut f: impl FnMut(&TcpOptionElement) -> Option<R>) -> Option<R> {
+        self.tcp(|tcph| {
+            tcph.options_iterator().map_while(Result::ok).find_map(|opt| f(&opt))
+        }).flatten()
     }
 }
 
@@ -120,23 +474,155 @@ impl Deref for Packet {
     type Target = [u8];
 
     fn deref(&self) -> &Self::Target {
-        &self.data
+        self.data()
     }
 }
 
+// 克隆出来的包总是自己持有数据
+impl Clone for Packet {
+    fn clone(&self) -> Self {
+        let state = match self.state {
+            DataState::Borrowed => DataState::Owned,
+            state => state,
+        };
+        Packet::from_owned(self.timestamp, self.link_type, self.data().into(), self.header.borrow().clone(), state)
+    }
+}
+
+impl PartialEq for Packet {
+    fn eq(&self, other: &Self) -> bool {
+        self.timestamp == other.timestamp && self.link_type == other.link_type && self.data() == other.data() && self.header == other.header
+    }
+}
+
+impl Eq for Packet {}
+
 impl fmt::Debug for Packet {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(header) = self.header.borrow().as_ref() {
+            write!(
+                f,
+                "{:?}:{} -> {:?}:{}, ",
+                header.saddr(),
+                header.sport(),
+                header.daddr(),
+                header.dport()
+            )?;
+        }
         write!(
             f,
-            "ip: {:?}, Packet: ts: {}, caplen: {}, data: {:?}",
-            self.header.borrow().as_ref().unwrap().ip,
+            "Packet: ts: {}, caplen: {}, data: {:?}",
             self.timestamp,
-            self.data_len,
-            self.data
+            self.data_len(),
+            self.data()
         )
     }
 }
 
+// ip头中记录的传输层载荷长度。以太网最小帧会带填充，不能直接用捕获长度计算
+// ipv6的jumbogram载荷长度为0，此时返回None，由捕获长度决定
+fn ip_payload_len(ip: &IpHeader, transport: &TransportHeader) -> Option<usize> {
+    let ip_payload_len = match ip {
+        IpHeader::Version4(ipv4h, ext) => (ipv4h.payload_len as usize).checked_sub(ext.header_len())?,
+        IpHeader::Version6(ipv6h, ext) => {
+            if ipv6h.payload_length == 0 {
+                return None;
+            }
+            (ipv6h.payload_length as usize).checked_sub(ext.header_len())?
+        }
+    };
+    Some(ip_payload_len.saturating_sub(transport.header_len()))
+}
+
+// 传输层头的位置
+fn csum_offset(header: &PktHeader) -> usize {
+    let len = header.transport.as_ref().map_or(0, |transport| transport.header_len());
+    header.payload_offset.saturating_sub(len)
+}
+
+// 校验ip头和传输层的校验和，错误时返回出错的头的位置。传输层的数据被截断时返回None
+fn csum_check(header: &PktHeader, data: &[u8]) -> Option<Option<usize>> {
+    let ip = header.ip.as_ref()?;
+    let transport = header.transport.as_ref()?;
+    let captured = data.len().checked_sub(header.payload_offset)?;
+    let len = ip_payload_len(ip, transport).unwrap_or(captured);
+    if len > captured {
+        return None;
+    }
+    let payload = &data[header.payload_offset..header.payload_offset + len];
+
+    let transport_offset = csum_offset(header);
+    if let IpHeader::Version4(ipv4h, ext) = ip {
+        if ipv4h.calc_header_checksum().ok()? != ipv4h.header_checksum {
+            return Some(Some(transport_offset.saturating_sub(ipv4h.header_len() + ext.header_len())));
+        }
+    }
+
+    let good = match (ip, transport) {
+        (IpHeader::Version4(ipv4h, _), TransportHeader::Tcp(tcph)) => {
+            tcph.calc_checksum_ipv4(ipv4h, payload).ok()? == tcph.checksum
+        }
+        (IpHeader::Version4(ipv4h, _), TransportHeader::Udp(udph)) => {
+            // ipv4的udp校验和为0表示没有校验和
+            udph.checksum == 0 || udph.calc_checksum_ipv4(ipv4h, payload).ok()? == udph.checksum
+        }
+        (IpHeader::Version6(ipv6h, _), TransportHeader::Tcp(tcph)) => {
+            tcph.calc_checksum_ipv6(ipv6h, payload).ok()? == tcph.checksum
+        }
+        (IpHeader::Version6(ipv6h, _), TransportHeader::Udp(udph)) => {
+            udph.calc_checksum_ipv6(ipv6h, payload).ok()? == udph.checksum
+        }
+        _ => return None,
+    };
+    Some((!good).then_some(transport_offset))
+}
+
+// 解码失败的原因，offset是出错的位置，从帧的开头算起
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PacketError {
-    DecodeErr
+    Truncated { offset: usize },                            // 数据不够，offset是实际的长度
+    UnsupportedEtherType { offset: usize, ether_type: u16 },
+    NonIp { offset: usize },                                // 链路层或者隧道里不是ip
+    NonTcpUdp { offset: usize, proto: u8 },
+    Oversize { offset: usize },                             // 超过MAX_PACKET_LEN
+    MalformedOptions { offset: usize },                     // ip选项，ipv6扩展头或者tcp选项格式错误
+    Fragmented { offset: usize },                           // ip分片，需要先经过Defrag
+    TunnelDepth { offset: usize },                          // 隧道超过MAX_TUNNEL_DEPTH层
+    BadCsum { offset: usize },                              // 校验和错误，CsumPolicy::Drop时返回
+}
+
+impl PacketError {
+    pub fn offset(&self) -> usize {
+        match *self {
+            PacketError::Truncated { offset }
+            | PacketError::UnsupportedEtherType { offset, .. }
+            | PacketError::NonIp { offset }
+            | PacketError::NonTcpUdp { offset, .. }
+            | PacketError::Oversize { offset }
+            | PacketError::MalformedOptions { offset }
+            | PacketError::Fragmented { offset }
+            | PacketError::TunnelDepth { offset }
+            | PacketError::BadCsum { offset } => offset,
+        }
+    }
 }
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            PacketError::Truncated { offset } => write!(f, "packet truncated at offset {}", offset),
+            PacketError::UnsupportedEtherType { offset, ether_type } => {
+                write!(f, "unsupported ether type 0x{:04x} at offset {}", ether_type, offset)
+            }
+            PacketError::NonIp { offset } => write!(f, "non-ip payload at offset {}", offset),
+            PacketError::NonTcpUdp { offset, proto } => write!(f, "non-tcp/udp protocol {} at offset {}", proto, offset),
+            PacketError::Oversize { offset } => write!(f, "packet longer than {} bytes", offset),
+            PacketError::MalformedOptions { offset } => write!(f, "malformed options at offset {}", offset),
+            PacketError::Fragmented { offset } => write!(f, "ip fragment at offset {}", offset),
+            PacketError::TunnelDepth { offset } => write!(f, "too many tunnel layers at offset {}", offset),
+            PacketError::BadCsum { offset } => write!(f, "bad checksum at offset {}", offset),
+        }
+    }
+}
+
+impl std::error::Error for PacketError {}
@@ -28,3 +28,18 @@ pub trait Parser {
         Box::pin(async move {})
     }
 }
+
+// 让FlowTable的工厂函数可以返回不同类型的解析器
+impl<P: Parser + ?Sized> Parser for Box<P> {
+    fn c2s_parser(&self, stream: *const PktStrm, meta_tx: mpsc::Sender<Meta>) -> Pin<Box<dyn Future<Output = ()>>> {
+        (**self).c2s_parser(stream, meta_tx)
+    }
+
+    fn s2c_parser(&self, stream: *const PktStrm, meta_tx: mpsc::Sender<Meta>) -> Pin<Box<dyn Future<Output = ()>>> {
+        (**self).s2c_parser(stream, meta_tx)
+    }
+
+    fn bdir_parser(&self, c2s_stream: *const PktStrm, s2c_stream: *const PktStrm, meta_tx: mpsc::Sender<Meta>) -> Pin<Box<dyn Future<Output = ()>>> {
+        (**self).bdir_parser(c2s_stream, s2c_stream, meta_tx)
+    }
+}
@@ -12,6 +12,10 @@ use std::fmt;
 use crate::Meta;
 use crate::Parser;
 use crate::PktStrm;
+use crate::trim_crlf;
+
+// rfc5321中文本行最长1000字节，包括\r\n
+const MAX_LINE: usize = 1000;
 
 pub enum MetaSmtp {
     User(String),
@@ -40,22 +44,28 @@ impl Parser for SmtpParser {
             let stm: &mut PktStrm;
             unsafe { stm = &mut *(stream as *mut PktStrm); }
 
+            // 中途接入时第一行可能不完整。不像命令的行丢掉，从下一行开始
+            let first = stm.peek_until(b'\n').await;
+            if stm.is_midstream() && !is_command(&first) {
+                stm.resync_line().await;
+            }
+
             // 忽略前面不需要的命令
-            let _ = stm.readline().await;
-            let _ = stm.readline().await;            
+            let Some(_) = read_line(stm).await else { return };
+            let Some(_) = read_line(stm).await else { return };
 
             // user
-            let user = stm.readline().await.unwrap().trim_end_matches("\r\n").to_string();
+            let Some(user) = read_line(stm).await else { return };
             let meta = Meta::Smtp(MetaSmtp::User(user));
             let _ = meta_tx.send(meta).await;
             
             // pass
-            let pass = stm.readline().await.unwrap().trim_end_matches("\r\n").to_string();
+            let Some(pass) = read_line(stm).await else { return };
             let meta = Meta::Smtp(MetaSmtp::Pass(pass));
             let _ = meta_tx.send(meta).await;
 
             // mail from
-            let line = stm.readline().await.unwrap().trim_end_matches("\r\n").to_string();
+            let Some(line) = read_line(stm).await else { return };
             match mail_from(&line) {
                 Ok((_, (email, size))) => {
                     let meta = Meta::Smtp(MetaSmtp::MailFrom(email.to_string(), size));
@@ -65,7 +75,7 @@ impl Parser for SmtpParser {
             }
 
             // rcpt to
-            let line = stm.readline().await.unwrap().trim_end_matches("\r\n").to_string();
+            let Some(line) = read_line(stm).await else { return };
             match rcpt_to(&line) {
                 Ok((_, mail)) => {
                     let meta = Meta::Smtp(MetaSmtp::RcptTo(mail.to_string()));
@@ -75,7 +85,7 @@ impl Parser for SmtpParser {
             }
 
             // DATA
-            let _ = stm.readline().await.unwrap().trim_end_matches("\r\n").to_string();            
+            let Some(_) = read_line(stm).await else { return };
 
             // mail head
             let (_content_type, _bdry) = mail_head(stm, &mut meta_tx).await;
@@ -83,6 +93,27 @@ impl Parser for SmtpParser {
     }
 }
 
+// 去掉行尾的\r\n，非utf8的字节替换掉。超长的行丢弃前面的部分。
+// 遇到缺失时丢掉缺失前后不完整的行，从下一行接着读。流结束时返回None
+async fn read_line(stm: &mut PktStrm) -> Option<String> {
+    loop {
+        let Ok(line) = stm.read_line_limited(MAX_LINE).await else { continue };
+        if !line.ends_with(b"\n") && stm.take_gap().is_some() {
+            stm.resync_line().await;
+            continue;
+        }
+        if line.is_empty() {
+            return None;
+        }
+        return Some(String::from_utf8_lossy(trim_crlf(&line)).into_owned());
+    }
+}
+
+// 命令是4个字母，后面跟空格或者行尾
+fn is_command(line: &[u8]) -> bool {
+    line.len() > 4 && line[..4].iter().all(u8::is_ascii_alphabetic) && matches!(line[4], b' ' | b'\r' | b'\n')
+}
+
 enum ContentType {
     Unknown,
     Alt,
@@ -110,8 +141,8 @@ async fn mail_head(stm: &mut PktStrm, meta_tx: &mut mpsc::Sender<Meta>) -> (Cont
     let mut boundary = String::new();
     
     loop {
-        let line = stm.readline().await.unwrap();
-        if line == "\r\n" {
+        let Some(line) = read_line(stm).await else { break };
+        if line.is_empty() {
             break;
         }
 
@@ -1,44 +1,321 @@
 use core::cmp::Ordering;
 use std::cmp::Reverse;
-use etherparse::TransportHeader;
-use std::collections::BinaryHeap;
+use etherparse::{TcpOptionElement, TransportHeader};
+use std::collections::{BinaryHeap, VecDeque};
+use std::cell::Cell;
+use std::ops::Deref;
 use std::rc::Rc;
-use futures_util::stream::{Stream, StreamExt};
-use std::task::Poll;
-use futures::future;
+use futures_util::stream::Stream;
+use futures::io::{AsyncBufRead, AsyncRead};
+use futures::ready;
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 use futures::Future;
 use futures::future::poll_fn;
 use crate::Packet;
+use crate::CsumState;
 
-const MAX_CACHE_PKTS: usize = 32;
+pub const DEFAULT_MAX_PKTS: usize = 32;
+pub const DEFAULT_MAX_BYTES: usize = 256 * 1024;
+const MAX_BASE_DIST: u32 = 1 << 30; // next_seq离排序基准超过这个距离时，移动基准
+pub const DEFAULT_STRM_TIMEOUT: u128 = 60_000; // 毫秒
+
+// 多个流共用的内存预算，按缓存的载荷字节数计算。用Rc在流之间共享
+#[derive(Debug)]
+pub struct MemBudget {
+    limit: usize,
+    used: Cell<usize>,
+}
+
+impl MemBudget {
+    pub fn new(limit: usize) -> Self {
+        MemBudget { limit, used: Cell::new(0) }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.get()
+    }
+
+    fn charge(&self, add: usize, sub: usize) {
+        self.used.set((self.used.get() + add).saturating_sub(sub));
+    }
+}
+
+// 流的统计。有丢弃或者缺失时，重组的数据不完整
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StrmStats {
+    pub dropped_pkts: u64,  // 因为缓存限制丢弃的包，包括被淘汰的
+    pub dropped_bytes: u64,
+    pub gaps: u64,          // 跳过的缺失
+    pub gap_bytes: u64,
+}
+
+// 重叠的数据不一致时，采用哪个包的数据。只对还没有读走的数据有效
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverlapPolicy {
+    #[default]
+    First,   // 先到的包优先
+    Last,    // 后到的包优先
+    Bsd,     // 先到的优先，除非后到的起始seq更小
+    Linux,   // 同Bsd，另外起始seq相同且更长时后到的优先
+    Windows, // 先到的优先，除非后到的起始seq更小且完全覆盖先到的
+}
+
+// 流中的异常事件
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Anomaly {
+    Overlap { seq: u32, len: u32 }, // 重叠部分的数据不一致，常见的规避检测的手段
+    BadRst { seq: u32 },            // 不在对端接收窗口内的rst，可能是伪造的或者过时的
+}
+
+// 被跳过的缺失数据，从seq开始的len个字节
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Gap {
+    pub seq: u32,
+    pub len: u32,
+}
 
 #[derive(Debug, Clone)]
 pub struct PktStrm {
     cache: BinaryHeap<Reverse<SeqPacket>>,
+    bytes: usize,              // 缓存的载荷字节数
+    max_pkts: usize,
+    max_bytes: usize,
+    budget: Option<Rc<MemBudget>>,
+    stats: StrmStats,
+    isn: Option<u32>,          // 开始读之前见到的最小的seq，有syn时就是syn的seq
+    base: u32,                 // 缓存排序的基准，seq按相对基准的偏移排序
+    started: bool,             // next_seq是否有效
     next_seq: u32,             // 下一个要读取的seq
-    fin: bool
+    fin: bool,
+    last_ts: Option<u128>,     // 最后一个包的时间戳
+    timeout: u128,
+    expired: bool,             // 超时之后不再等缺失的数据
+    gaps: VecDeque<Gap>,       // 跳过了但解析器还没有取走的缺失
+    overlap_policy: OverlapPolicy,
+    anomalies: VecDeque<Anomaly>,
+    read_buf: Option<PktChunk>, // poll_fill_buf返回的数据
+    rst: bool,                 // 收到了有效的rst
+    wscale: Option<u8>,        // syn中的窗口扩大因子
+    peer_ack: Option<u32>,     // 对端确认到的seq
+    peer_win: u32,             // 对端的接收窗口，已经按扩大因子换算成字节
+    midstream: bool,           // 第一个包不是syn，从连接中途开始
+    midstream_wait: u128,      // 中途接入时，开始读之前等待更早的包的时间
+    wait_until: Option<u128>,
+    discarded: bool,           // 没有解析器再读
 }
 
 impl PktStrm {
     pub fn new() -> Self {
         PktStrm {
-            cache: BinaryHeap::with_capacity(MAX_CACHE_PKTS),
+            cache: BinaryHeap::with_capacity(DEFAULT_MAX_PKTS),
+            bytes: 0,
+            max_pkts: DEFAULT_MAX_PKTS,
+            max_bytes: DEFAULT_MAX_BYTES,
+            budget: None,
+            stats: StrmStats::default(),
+            isn: None,
+            base: 0,
+            started: false,
             next_seq: 0,
-            fin: false
+            fin: false,
+            last_ts: None,
+            timeout: DEFAULT_STRM_TIMEOUT,
+            expired: false,
+            gaps: VecDeque::new(),
+            overlap_policy: OverlapPolicy::default(),
+            anomalies: VecDeque::new(),
+            read_buf: None,
+            rst: false,
+            wscale: None,
+            peer_ack: None,
+            peer_win: 0,
+            midstream: false,
+            midstream_wait: 0,
+            wait_until: None,
+            discarded: false,
         }
     }
     
     // 放入缓存，准备重组
     pub fn push(&mut self, pkt: Rc<Packet>) {
-        if let Some(TransportHeader::Tcp(_)) = &pkt.header.borrow().as_ref().unwrap().transport {
-            if self.cache.len() >= MAX_CACHE_PKTS {
+        let header = pkt.header.borrow();
+        let header = header.as_ref().unwrap();
+        // 校验和错误被标记的包不参与重组
+        if header.csum == CsumState::Flagged {
+            return;
+        }
+        if let Some(TransportHeader::Tcp(tcph)) = &header.transport {
+            self.last_ts = Some(self.last_ts.map_or(pkt.timestamp, |ts| ts.max(pkt.timestamp)));
+            let seq = pkt.seq();
+            if tcph.rst {
+                if self.rst_in_window(seq) {
+                    self.reset();
+                } else {
+                    self.anomalies.push_back(Anomaly::BadRst { seq });
+                }
                 return;
             }
-            
-            self.cache.push(Reverse(SeqPacket(Rc::clone(&pkt))));
+            if tcph.syn {
+                self.wscale = tcph.options_iterator().find_map(|opt| match opt {
+                    Ok(TcpOptionElement::WindowScale(scale)) => Some(scale.min(14)),
+                    _ => None,
+                });
+            }
+            match self.isn {
+                None => {
+                    self.isn = Some(seq);
+                    self.base = seq;
+                    self.midstream = !tcph.syn;
+                    if self.midstream && self.midstream_wait > 0 {
+                        self.wait_until = Some(pkt.timestamp.saturating_add(self.midstream_wait));
+                    }
+                }
+                Some(isn) if !self.started && seq_lt(seq, isn) => {
+                    self.isn = Some(seq);
+                    self.midstream = !tcph.syn;
+                    self.rebase(seq);
+                }
+                _ if self.started && self.next_seq.wrapping_sub(self.base) > MAX_BASE_DIST => self.rebase(self.next_seq),
+                _ => {}
+            }
+            let mut view = SeqPacket::new(Rc::clone(&pkt), self.base);
+            if !self.make_room(&view) {
+                self.stats.dropped_pkts += 1;
+                self.stats.dropped_bytes += view.len as u64;
+                return;
+            }
+            // 调用者的内存在Task::run返回后就会被复用，缓存的是只有载荷的拷贝
+            if pkt.is_borrowed() {
+                view.pkt = pkt.detached();
+            }
+            self.insert(view);
+        }
+    }
+
+    // 新的包和缓存中数据不一致的部分按重叠策略处理，保证缓存中重叠的部分数据相同。
+    // 完全相同的重传原样放入，读的时候去重
+    fn insert(&mut self, new: SeqPacket) {
+        let conflict = |old: &SeqPacket| new.overlap(old).is_some_and(|(start, end)| new.bytes(start, end) != old.bytes(start, end));
+        if !self.cache.iter().any(|Reverse(old)| conflict(old)) {
+            self.charge(new.len as usize, 0);
+            self.cache.push(Reverse(new));
+            return;
+        }
+
+        let mut kept = Vec::with_capacity(self.cache.len() + 2);
+        let mut pieces = vec![new.clone()]; // 新包还没有被覆盖的部分
+        let mut span: Option<(u32, u32)> = None; // 不一致的范围，一个包只报告一次
+        for Reverse(old) in self.cache.drain() {
+            if !conflict(&old) {
+                kept.push(old);
+                continue;
+            }
+            let (start, end) = new.overlap(&old).unwrap();
+            span = Some(match span {
+                Some((s, e)) => (if seq_lt(start, s) { start } else { s }, if seq_lt(e, end) { end } else { e }),
+                None => (start, end),
+            });
+            if self.overlap_policy.new_wins(&old, &new) {
+                kept.extend(old.cut(new.seq, new.end(), self.base));
+            } else {
+                pieces = pieces.iter().flat_map(|piece| piece.cut(old.seq, old.end(), self.base)).collect();
+                kept.push(old);
+            }
+        }
+        kept.extend(pieces);
+        let bytes = kept.iter().map(|view| view.len as usize).sum();
+        self.charge(bytes, self.bytes);
+        self.cache = kept.into_iter().map(Reverse).collect();
+        if let Some((start, end)) = span {
+            self.anomalies.push_back(Anomaly::Overlap { seq: start, len: end.wrapping_sub(start) });
         }
     }
     
+    // 超过缓存限制时腾出空间：先跳过挡住读取的缺失，让缓存中的数据可以被读走；
+    // 再淘汰第一个缺失之后离next_seq最远的包。新包本身最远，或者都不行，返回false丢弃新包
+    fn make_room(&mut self, new: &SeqPacket) -> bool {
+        let mut skipped = false;
+        while self.is_full(new.len as usize) {
+            if !skipped && self.skip_gap().is_some() {
+                skipped = true;
+                continue;
+            }
+            if !self.evict(new.key) {
+                return skipped;
+            }
+        }
+        true
+    }
+
+    fn is_full(&self, len: usize) -> bool {
+        self.cache.len() >= self.max_pkts
+            || self.bytes + len > self.max_bytes
+            || self.budget.as_ref().is_some_and(|budget| budget.used() + len > budget.limit())
+    }
+
+    fn evict(&mut self, new_key: u32) -> bool {
+        let mut views: Vec<SeqPacket> = self.cache.drain().map(|Reverse(view)| view).collect();
+        views.sort_by_key(|view| view.key);
+        let mut end = if self.started { self.next_seq } else { views.first().map_or(0, |view| view.seq) };
+        let hole = views.iter().any(|view| {
+            if seq_lt(end, view.seq) {
+                return true;
+            }
+            if seq_lt(end, view.end()) {
+                end = view.end();
+            }
+            false
+        });
+        let evicted = if hole && views.last().is_some_and(|view| view.key > new_key) { views.pop() } else { None };
+        if let Some(view) = &evicted {
+            self.charge(0, view.len as usize);
+            self.stats.dropped_pkts += 1;
+            self.stats.dropped_bytes += view.len as u64;
+        }
+        self.cache = views.into_iter().map(Reverse).collect();
+        evicted.is_some()
+    }
+
+    fn charge(&mut self, add: usize, sub: usize) {
+        self.bytes = (self.bytes + add).saturating_sub(sub);
+        if let Some(budget) = &self.budget {
+            budget.charge(add, sub);
+        }
+    }
+
+    pub fn set_max_pkts(&mut self, max_pkts: usize) {
+        self.max_pkts = max_pkts;
+    }
+
+    pub fn set_max_bytes(&mut self, max_bytes: usize) {
+        self.max_bytes = max_bytes;
+    }
+
+    // 和其他流共用内存预算
+    pub fn set_budget(&mut self, budget: Rc<MemBudget>) {
+        if let Some(old) = &self.budget {
+            old.charge(0, self.bytes);
+        }
+        budget.charge(self.bytes, 0);
+        self.budget = Some(budget);
+    }
+
+    pub fn stats(&self) -> StrmStats {
+        self.stats
+    }
+
+    // 缓存的载荷字节数
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
     pub fn len(&self) -> usize {
         self.cache.len()
     }
@@ -49,25 +326,383 @@ impl PktStrm {
     
     pub fn clear(&mut self) {
         self.cache.clear();
+        self.read_buf = None;
+        self.charge(0, self.bytes);
     }
 
-    pub fn timeout(&self) {
+    // 没有解析器再读的流：清空缓存，之后next_seq不再前进，也不再用确认号判断丢包
+    pub fn discard(&mut self) {
+        self.discarded = true;
+        self.clear();
     }
 
+    pub fn set_timeout(&mut self, timeout: u128) {
+        self.timeout = timeout;
+    }
+
+    pub fn set_overlap_policy(&mut self, policy: OverlapPolicy) {
+        self.overlap_policy = policy;
+    }
+
+    pub fn take_anomaly(&mut self) -> Option<Anomaly> {
+        self.anomalies.pop_front()
+    }
+
+    pub fn isn(&self) -> Option<u32> {
+        self.isn
+    }
+
+    // 改变排序基准，重新排序缓存中的包
+    fn rebase(&mut self, base: u32) {
+        self.base = base;
+        self.cache = self.cache.drain().map(|Reverse(entry)| Reverse(entry.rekey(base))).collect();
+    }
+
+    pub fn last_ts(&self) -> Option<u128> {
+        self.last_ts
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expired
+    }
+
+    // 超过timeout没有新的包，就不再等待。返回这次调用是否超时，结束了中途接入的等待，
+    // 或者跳过了被确认的缺失。还没有收到过包的流不会超时。now和Packet::timestamp单位相同
+    pub fn timeout(&mut self, now: u128) -> bool {
+        let mut waited = self.peer_ack.is_some_and(|ack| self.ack_lost(ack, true));
+        if let Some(until) = self.wait_until {
+            if !self.started && now >= until {
+                self.wait_until = None;
+                waited = true;
+            }
+        }
+        match self.last_ts {
+            Some(ts) if !self.expired && now.saturating_sub(ts) > self.timeout => {
+                self.expire();
+                true
+            }
+            _ => waited,
+        }
+    }
+
+    // 第一个包没有syn，从连接中途开始
+    pub fn is_midstream(&self) -> bool {
+        self.midstream
+    }
+
+    // 中途接入的流，开始读之前最多等待wait，让乱序的更早的包有机会到达，从其中最小的seq开始。
+    // 默认为0，从第一个包开始。在第一个包之前设置
+    pub fn set_midstream_wait(&mut self, wait: u128) {
+        self.midstream_wait = wait;
+    }
+
+    // 还在等待更早的包。流结束或者缓存满了不再等
+    fn waiting(&self) -> bool {
+        self.midstream
+            && !self.expired
+            && !self.is_full(1)
+            && self.wait_until.zip(self.last_ts).is_some_and(|(until, ts)| ts < until)
+    }
+
+    // 当作收到了fin：跳过缺失的数据，读完缓存中的数据之后结束
+    pub fn expire(&mut self) {
+        self.expired = true;
+        self.fin = true;
+    }
+
+    // 连接被rst重置。同expire，is_rst用来区分正常结束
+    pub fn reset(&mut self) {
+        self.rst = true;
+        self.expire();
+    }
+
+    pub fn is_rst(&self) -> bool {
+        self.rst
+    }
+
+    // syn中的窗口扩大因子，没有收到syn或者syn中没有时为None
+    pub fn wscale(&self) -> Option<u8> {
+        self.wscale
+    }
+
+    // 对端的确认号和接收窗口(字节)，由反方向的包得到。用来检查rst是否有效，
+    // 以及确认丢包。返回是否因此跳过了缺失
+    pub fn update_peer(&mut self, ack: u32, win: u32) -> bool {
+        if !self.peer_ack.is_none_or(|old| seq_le(old, ack)) {
+            return false;
+        }
+        self.peer_ack = Some(ack);
+        self.peer_win = win;
+        self.ack_lost(ack, false)
+    }
+
+    // next_seq处缺失的数据已经被对端确认，说明对端收到了但是没有抓到，不会再有重传。
+    // 缓存中已经有缺失之后的数据时直接跳过被确认的部分，后面的数据可以马上读走。
+    // 否则可能只是抓包点乱序，确认先于数据到达，等到下一次timeout时还没到才跳过
+    fn ack_lost(&mut self, ack: u32, force: bool) -> bool {
+        if self.discarded || !self.started || self.fin || !seq_lt(self.next_seq, ack) {
+            return false;
+        }
+        self.top_pkt_dedup();
+        let end = match self.top() {
+            Some(view) if seq_le(view.seq, self.next_seq) => return false,
+            Some(view) if seq_lt(view.seq, ack) => view.seq,
+            Some(_) => ack,
+            None if force => ack,
+            None => return false,
+        };
+        self.skip_to(end);
+        true
+    }
+
+    // rfc5961，rst的seq在对端的接收窗口内才有效。还不知道对端窗口时都认为有效
+    fn rst_in_window(&self, seq: u32) -> bool {
+        match self.peer_ack {
+            Some(ack) => seq_le(ack, seq) && seq_lt(seq, ack.wrapping_add(self.peer_win.max(1))),
+            None => true,
+        }
+    }
+
+    // 放弃等待next_seq处缺失的数据，跳到缓存中最小的seq。返回跳过的部分，没有缺失时返回None
+    pub fn skip_gap(&mut self) -> Option<Gap> {
+        if !self.started {
+            return None;
+        }
+        self.top_pkt_dedup();
+        let seq = self.top()?.seq;
+        if seq_le(seq, self.next_seq) {
+            return None;
+        }
+        let gap = Gap { seq: self.next_seq, len: seq.wrapping_sub(self.next_seq) };
+        self.skip_to(seq);
+        Some(gap)
+    }
+
+    // next_seq跳到seq，记录缺失。和还没有被取走的上一个缺失相连时合并成一个
+    fn skip_to(&mut self, seq: u32) {
+        let len = seq.wrapping_sub(self.next_seq);
+        self.stats.gap_bytes += len as u64;
+        match self.gaps.back_mut() {
+            Some(last) if last.seq.wrapping_add(last.len) == self.next_seq => last.len += len,
+            _ => {
+                self.gaps.push_back(Gap { seq: self.next_seq, len });
+                self.stats.gaps += 1;
+            }
+        }
+        self.next_seq = seq;
+    }
+
+    // 读到缺失处时，readn和readline返回缺失之前的数据，之后一直读不到数据，
+    // 直到解析器取走缺失，确认需要重新同步
+    pub fn take_gap(&mut self) -> Option<Gap> {
+        self.gaps.pop_front()
+    }
+
+    pub fn has_gap(&self) -> bool {
+        !self.gaps.is_empty()
+    }
+
+    // 流结束或者遇到缺失时，返回的数据少于num
     pub async fn readn(&mut self, num: usize) -> Vec<u8> {
-        self.take(num).collect::<Vec<u8>>().await
+        let mut res = Vec::with_capacity(num);
+        while res.len() < num {
+            match self.next_chunk(num - res.len()).await {
+                Some(chunk) => res.extend_from_slice(&chunk),
+                None => break,
+            }
+        }
+        res
     }
-    
+
+    // 包括行尾的换行。流结束或者遇到缺失时，返回的行没有换行
     pub async fn readline(&mut self) -> Result<String, std::string::FromUtf8Error> {
-        let mut res = self.take_while(|x| future::ready(*x != b'\n')).collect::<Vec<u8>>().await;
-        if res.is_empty() {
-            String::from_utf8(res)
-        } else {
-            res.push(b'\n');
-            String::from_utf8(res)
+        String::from_utf8(self.read_line_bytes().await)
+    }
+
+    // 同readline，不要求utf8
+    pub async fn read_line_bytes(&mut self) -> Vec<u8> {
+        self.read_until(b'\n').await
+    }
+
+    // 读到delim为止，包括delim。流结束或者遇到缺失时，返回的数据没有delim
+    pub async fn read_until(&mut self, delim: u8) -> Vec<u8> {
+        self.read_until_max(delim, usize::MAX).await
+    }
+
+    // 同read_line_bytes，但行长度(包括换行)不超过max。读了max字节还没有换行时，
+    // 这些字节被丢弃，返回LineTooLong，之后从下一个字节接着读
+    pub async fn read_line_limited(&mut self, max: usize) -> Result<Vec<u8>, LineTooLong> {
+        let line = self.read_until_max(b'\n', max).await;
+        if line.len() >= max && line.last() != Some(&b'\n') {
+            return Err(LineTooLong { max });
+        }
+        Ok(line)
+    }
+
+    // 以\r\n结尾的行，包括\r\n。单独的\n不算行尾。长度限制同read_line_limited
+    pub async fn read_crlf_line(&mut self, max: usize) -> Result<Vec<u8>, LineTooLong> {
+        let mut line = Vec::new();
+        loop {
+            let part = self.read_until_max(b'\n', max - line.len()).await;
+            let newline = part.last() == Some(&b'\n');
+            line.extend_from_slice(&part);
+            if line.ends_with(b"\r\n") {
+                return Ok(line);
+            }
+            if line.len() >= max {
+                return Err(LineTooLong { max });
+            }
+            if !newline {
+                return Ok(line);
+            }
         }
     }
 
+    // 丢弃到下一个行首为止的数据，返回丢弃的字节数。用于中途接入或者缺失之后，
+    // 按行解析的解析器重新同步。流中没有记录前一个字节，无法判断当前是否在行首，
+    // 恰好在行首时也会丢掉完整的一行。需要保留这一行的解析器自己先peek判断
+    pub async fn resync_line(&mut self) -> usize {
+        let mut skipped = 0;
+        while let Some(chunk) = poll_fn(|_cx| self.poll_chunk()).await {
+            match chunk.iter().position(|byte| *byte == b'\n') {
+                Some(pos) => {
+                    self.consume(pos + 1);
+                    return skipped + pos + 1;
+                }
+                None => {
+                    skipped += chunk.len();
+                    self.consume(chunk.len());
+                }
+            }
+        }
+        skipped
+    }
+
+    // 读到delim，读满max字节，流结束或者遇到缺失时返回
+    async fn read_until_max(&mut self, delim: u8, max: usize) -> Vec<u8> {
+        let mut res = Vec::new();
+        while res.len() < max {
+            let chunk = match poll_fn(|_cx| self.poll_chunk()).await {
+                Some(chunk) => chunk,
+                None => break,
+            };
+            let data = &chunk[..chunk.len().min(max - res.len())];
+            match data.iter().position(|byte| *byte == delim) {
+                Some(pos) => {
+                    res.extend_from_slice(&data[..=pos]);
+                    self.consume(pos + 1);
+                    break;
+                }
+                None => {
+                    res.extend_from_slice(data);
+                    self.consume(data.len());
+                }
+            }
+        }
+        res
+    }
+
+    // 异步方式读取下一段连续的数据，最多max字节，读到的数据被消费掉。
+    // 流结束或者遇到缺失时返回None
+    pub fn next_chunk(&mut self, max: usize) -> impl Future<Output = Option<PktChunk>> + '_ {
+        poll_fn(move |_cx| {
+            match self.poll_chunk() {
+                Poll::Ready(Some(mut chunk)) => {
+                    chunk.truncate(max);
+                    self.consume(chunk.len());
+                    Poll::Ready(Some(chunk))
+                }
+                other => other,
+            }
+        })
+    }
+
+    // 当前可读的连续数据，从next_seq到所在包的末尾，不消费。没有可读的数据返回None
+    pub fn peek_chunk(&mut self) -> Option<PktChunk> {
+        match self.poll_chunk() {
+            Poll::Ready(chunk) => chunk,
+            Poll::Pending => None,
+        }
+    }
+
+    // 消费掉peek_chunk返回的前amt个字节。amt不能超过chunk的长度
+    pub fn consume(&mut self, amt: usize) {
+        self.next_seq = self.next_seq.wrapping_add(amt as u32);
+    }
+
+    // 异步方式查看接下来的n个字节，不移动读的位置。流结束，遇到缺失，或者缓存满时返回的数据少于n
+    pub fn peek(&mut self, n: usize) -> impl Future<Output = Vec<u8>> + '_ {
+        poll_fn(move |_cx| self.poll_peek(n, None))
+    }
+
+    // 同peek，查看到delim为止，包括delim
+    pub fn peek_until(&mut self, delim: u8) -> impl Future<Output = Vec<u8>> + '_ {
+        poll_fn(move |_cx| self.poll_peek(usize::MAX, Some(delim)))
+    }
+
+    // 从next_seq开始，跨包收集缓存中连续的数据，直到max字节或者delim
+    fn poll_peek(&mut self, max: usize, delim: Option<u8>) -> Poll<Vec<u8>> {
+        let mut res = Vec::new();
+        if self.peek_ord_data().is_none() || self.has_gap() {
+            if self.has_gap() || self.fin {
+                return Poll::Ready(res);
+            }
+            return Poll::Pending;
+        }
+
+        let mut views: Vec<&SeqPacket> = self.cache.iter().map(|Reverse(view)| view).collect();
+        views.sort();
+        let mut pos = self.next_seq;
+        let mut end = false;
+        for view in views {
+            if seq_lt(pos, view.seq) {
+                break;
+            }
+            if seq_lt(pos, view.end()) {
+                let data = view.bytes(pos, view.end());
+                let data = &data[..data.len().min(max - res.len())];
+                if let Some(index) = delim.and_then(|delim| data.iter().position(|byte| *byte == delim)) {
+                    res.extend_from_slice(&data[..=index]);
+                    return Poll::Ready(res);
+                }
+                res.extend_from_slice(data);
+                if res.len() >= max {
+                    return Poll::Ready(res);
+                }
+                pos = view.end();
+            }
+            if view.fin() && seq_le(view.end(), pos) {
+                end = true;
+                break;
+            }
+        }
+
+        // 超时之后缺失会被跳过，缓存满了也等不到更多的数据
+        if end || self.expired || self.is_full(1) {
+            return Poll::Ready(res);
+        }
+        Poll::Pending
+    }
+
+    // 有连续数据时返回Ready(Some)，流结束或者有待处理的缺失返回Ready(None)，否则Pending
+    fn poll_chunk(&mut self) -> Poll<Option<PktChunk>> {
+        let pkt = self.peek_ord_data();
+        if self.has_gap() {
+            return Poll::Ready(None);
+        }
+        if let (Some(pkt), Some(view)) = (pkt, self.top()) {
+            if seq_lt(self.next_seq, view.end()) {
+                let start = self.next_seq.wrapping_sub(pkt.seq()) as usize;
+                let end = start + view.end().wrapping_sub(self.next_seq) as usize;
+                return Poll::Ready(Some(PktChunk { pkt, seq: self.next_seq, start, end }));
+            }
+        }
+        if self.fin {
+            return Poll::Ready(None);
+        }
+        Poll::Pending
+    }
+
     // 异步方式获取下一个原始顺序的包。包含载荷为0的。如果cache中每到来一个包，就调用，那就是原始到来的包顺序
     pub fn next_raw_ord_pkt(&mut self) -> impl Future<Output = Option<Rc<Packet>>> + '_ {
         poll_fn(|_cx| {
@@ -95,32 +730,32 @@ impl PktStrm {
     // 无论是否严格seq连续，peek一个当前最有序的包
     // 不更新next_seq
     pub fn peek_pkt(&self) -> Option<Rc<Packet>> {
-        self.cache.peek().map(|rev_pkt| {
-            let SeqPacket(pkt) = &rev_pkt.0;
-            pkt.clone()
-        })
+        self.top().map(|view| view.pkt.clone())
+    }
+
+    fn top(&self) -> Option<&SeqPacket> {
+        self.cache.peek().map(|rev_pkt| &rev_pkt.0)
     }
     
     // 无论是否严格seq连续，都pop一个当前包。
     // 注意：next_seq由调用者负责
     pub fn pop_pkt(&mut self) -> Option<Rc<Packet>> {
-        if let Some(pkt) = self.cache.pop().map(|rev_pkt| rev_pkt.0.0) {
-            if pkt.fin() {
-                self.fin = true;
-            }
-            return Some(pkt);
+        let view = self.cache.pop()?.0;
+        self.charge(0, view.len as usize);
+        if view.fin() {
+            self.fin = true;
         }
-        None
+        Some(view.pkt)
     }
     
     // top位置去重（并非整个cache内部都去重）
     fn top_pkt_dedup(&mut self) {
-        while let Some(pkt) = self.peek_pkt() {
-            if (pkt.fin() && pkt.payload_len() == 0) || (pkt.syn() && pkt.payload_len() == 0) {
+        while let Some(view) = self.top() {
+            if view.len == 0 && (view.fin() || view.pkt.syn()) {
                 return;
             }
             
-            if pkt.seq() + pkt.payload_len() <= self.next_seq {
+            if seq_le(view.end(), self.next_seq) {
                 self.pop_pkt();
                 continue;
             }
@@ -130,18 +765,25 @@ impl PktStrm {
     
     // 严格有序。peek一个seq严格有序的包，可能包含payload为0的。如果当前top有序，就peek，否则就none。
     pub fn peek_ord_pkt(&mut self) -> Option<Rc<Packet>> {
-        if self.next_seq == 0 {
-            if let Some(pkt) = self.peek_pkt() {
-                self.next_seq = pkt.seq();
+        if !self.started {
+            if self.waiting() {
+                return None;
+            }
+            if let Some(view) = self.top() {
+                self.next_seq = view.seq;
+                self.started = true;
             }
             return self.peek_pkt();
         }
 
         self.top_pkt_dedup();
-        if let Some(pkt) = self.peek_pkt() {
-            if pkt.seq() <= self.next_seq {
-                return Some(pkt);
-            }
+        let seq = self.top()?.seq;
+        if self.expired && seq_lt(self.next_seq, seq) {
+            self.skip_gap();
+            return self.peek_pkt();
+        }
+        if seq_le(seq, self.next_seq) {
+            return self.peek_pkt();
         }
         None
     }
@@ -149,50 +791,80 @@ impl PktStrm {
     // 严格有序。弹出一个严格有序的包，可能包含载荷为0的。否则为none
     // 并不需要关心fin标记，这不是pkt这一层关心的问题
     pub fn pop_ord_pkt(&mut self) -> Option<Rc<Packet>> {
-        if let Some(pkt) = self.peek_ord_pkt() {
-            if pkt.syn() && pkt.payload_len() == 0 {
-                self.next_seq += 1;                
-            } else if self.next_seq == pkt.seq() {
-                self.next_seq += pkt.payload_len();                
-            } else if self.next_seq > pkt.seq() {
-                self.next_seq += pkt.payload_len() - (self.next_seq - pkt.seq());
+        let pkt = self.peek_ord_pkt()?;
+        let view = self.top()?;
+        if pkt.syn() && view.len == 0 {
+            // 重传的syn不再占用序号
+            if view.seq == self.next_seq {
+                self.next_seq = self.next_seq.wrapping_add(1);
             }
-
-            return self.pop_pkt();
+        } else {
+            self.advance();
         }
-        None
+        self.pop_pkt()
     }
     
     // 严格有序的数据。peek出一个带数据的严格有序的包。否则为none
     pub fn peek_ord_data(&mut self) -> Option<Rc<Packet>> {
-        while let Some(pkt) = self.peek_ord_pkt() {
-            if pkt.payload_len() == 0 {
-                self.pop_ord_pkt();
-                continue;
+        while self.peek_ord_pkt().is_some() {
+            if self.top()?.len != 0 {
+                break;
             }
-
-            break;
+            self.pop_ord_pkt();
         }
         self.peek_ord_pkt()
     }
     
     // 严格有序的数据。pop一个带数据的严格有序的包。否则为none
     pub fn pop_ord_data(&mut self) -> Option<Rc<Packet>> {
-        if let Some(pkt) = self.peek_ord_data() {
-            match self.next_seq.cmp(&pkt.seq()) {
-                std::cmp::Ordering::Equal => self.next_seq += pkt.payload_len(),
-                std::cmp::Ordering::Greater => self.next_seq += pkt.payload_len() - (self.next_seq - pkt.seq()),
-                std::cmp::Ordering::Less => {},
+        self.peek_ord_data()?;
+        self.advance();
+        self.pop_pkt()
+    }
+
+    // 有序的包被读走，next_seq移到包的末尾。和已读部分重叠的只前进没有读过的部分
+    fn advance(&mut self) {
+        if let Some(view) = self.top() {
+            let (seq, end) = (view.seq, view.end());
+            if seq_le(seq, self.next_seq) && seq_lt(self.next_seq, end) {
+                self.next_seq = end;
             }
-            return self.pop_pkt();            
         }
-        None
     }
 }
 
+// 去掉行尾的\r\n或者\n
+pub fn trim_crlf(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+// 读了max字节还没有遇到行尾
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineTooLong {
+    pub max: usize,
+}
+
+impl fmt::Display for LineTooLong {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line longer than {} bytes", self.max)
+    }
+}
+
+impl std::error::Error for LineTooLong {}
+
+// rfc1982序号比较，a在b之前
+pub fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+pub fn seq_le(a: u32, b: u32) -> bool {
+    a == b || seq_lt(a, b)
+}
+
 impl Drop for PktStrm {
     fn drop(&mut self) {
-        self.cache.clear();
+        self.clear();
     }
 }
 
@@ -205,27 +877,153 @@ impl Default for PktStrm {
 impl Stream for PktStrm {
     type Item = u8;
 
-    fn poll_next(mut self: std::pin::Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> std::task::Poll<Option<Self::Item>> {
-        if let Some(pkt) = self.peek_ord_data() {
-            let index = pkt.header.borrow().as_ref().unwrap().payload_offset as u32 + (self.next_seq - pkt.seq());
-            if (index as usize) < pkt.data_len {
-                self.next_seq += 1;
-                return Poll::Ready(Some(pkt.data[index as usize])); 
+    fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        match this.poll_chunk() {
+            Poll::Ready(Some(chunk)) => {
+                this.consume(1);
+                Poll::Ready(Some(chunk[0]))
             }
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
         }
-        if self.fin {
-            return Poll::Ready(None);
+    }
+}
+
+// 流结束或者遇到缺失时读到0字节。遇到缺失后take_gap取走缺失，就可以接着读
+impl AsyncBufRead for PktStrm {
+    fn poll_fill_buf(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        this.read_buf = ready!(this.poll_chunk());
+        Poll::Ready(Ok(this.read_buf.as_deref().unwrap_or(&[])))
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        let this = self.get_mut();
+        if let Some(chunk) = this.read_buf.take() {
+            PktStrm::consume(this, amt.min(chunk.len()));
         }
-        Poll::Pending
     }
 }
 
+impl AsyncRead for PktStrm {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let data = ready!(self.as_mut().poll_fill_buf(cx))?;
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        AsyncBufRead::consume(self, len);
+        Poll::Ready(Ok(len))
+    }
+}
+
+// 流中一段连续的载荷，引用包本身，不复制数据
+#[derive(Debug, Clone)]
+pub struct PktChunk {
+    pkt: Rc<Packet>,
+    seq: u32,   // 第一个字节的序号
+    start: usize, // 相对载荷的偏移
+    end: usize,
+}
+
+impl PktChunk {
+    pub fn seq(&self) -> u32 {
+        self.seq
+    }
+
+    pub fn packet(&self) -> &Rc<Packet> {
+        &self.pkt
+    }
+
+    // 只保留前len个字节
+    pub fn truncate(&mut self, len: usize) {
+        self.end = self.end.min(self.start + len);
+    }
+}
+
+impl Deref for PktChunk {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        let offset = self.pkt.header.borrow().as_ref().unwrap().payload_offset;
+        &self.pkt[offset + self.start..offset + self.end]
+    }
+}
+
+// 缓存的是包载荷中从seq开始的len个字节，重叠处理之后可能只是包的一部分。
+// key是seq相对排序基准的偏移。基准之前的包是旧的重传，排在最前面，会被去重
 #[derive(Debug, Clone)]
-struct SeqPacket(Rc<Packet>);
+struct SeqPacket {
+    pkt: Rc<Packet>,
+    seq: u32,
+    len: u32,
+    key: u32,
+}
+
+impl SeqPacket {
+    fn new(pkt: Rc<Packet>, base: u32) -> Self {
+        let (seq, len) = (pkt.seq(), pkt.payload_len());
+        SeqPacket { pkt, seq, len, key: 0 }.rekey(base)
+    }
+
+    fn rekey(mut self, base: u32) -> Self {
+        self.key = if seq_lt(self.seq, base) { 0 } else { self.seq.wrapping_sub(base) };
+        self
+    }
+
+    fn end(&self) -> u32 {
+        self.seq.wrapping_add(self.len)
+    }
+
+    // 包的fin在这一段的末尾
+    fn fin(&self) -> bool {
+        self.pkt.fin() && self.end() == self.pkt.seq().wrapping_add(self.pkt.payload_len())
+    }
+
+    fn overlap(&self, other: &SeqPacket) -> Option<(u32, u32)> {
+        let start = if seq_lt(self.seq, other.seq) { other.seq } else { self.seq };
+        let end = if seq_lt(self.end(), other.end()) { self.end() } else { other.end() };
+        seq_lt(start, end).then_some((start, end))
+    }
+
+    fn bytes(&self, start: u32, end: u32) -> &[u8] {
+        let offset = self.pkt.header.borrow().as_ref().unwrap().payload_offset;
+        let start = offset + start.wrapping_sub(self.pkt.seq()) as usize;
+        let end = offset + end.wrapping_sub(self.pkt.seq()) as usize;
+        &self.pkt[start..end]
+    }
+
+    // 去掉[start, end)之后剩下的部分。带fin的末尾被去掉时，留一个空的段保留fin
+    fn cut(&self, start: u32, end: u32, base: u32) -> Vec<SeqPacket> {
+        let mut pieces = Vec::with_capacity(2);
+        if seq_lt(self.seq, start) {
+            let len = start.wrapping_sub(self.seq).min(self.len);
+            pieces.push(SeqPacket { seq: self.seq, len, ..self.clone() });
+        }
+        if seq_lt(end, self.end()) {
+            let seq = if seq_lt(end, self.seq) { self.seq } else { end };
+            pieces.push(SeqPacket { seq, len: self.end().wrapping_sub(seq), ..self.clone() }.rekey(base));
+        } else if self.fin() {
+            pieces.push(SeqPacket { seq: self.end(), len: 0, ..self.clone() }.rekey(base));
+        }
+        pieces
+    }
+}
+
+impl OverlapPolicy {
+    fn new_wins(self, old: &SeqPacket, new: &SeqPacket) -> bool {
+        match self {
+            OverlapPolicy::First => false,
+            OverlapPolicy::Last => true,
+            OverlapPolicy::Bsd => seq_lt(new.seq, old.seq),
+            OverlapPolicy::Linux => seq_lt(new.seq, old.seq) || (new.seq == old.seq && seq_lt(old.end(), new.end())),
+            OverlapPolicy::Windows => seq_lt(new.seq, old.seq) && seq_le(old.end(), new.end()),
+        }
+    }
+}
 
 impl PartialEq for SeqPacket {
     fn eq(&self, other: &Self) -> bool {
-        self.0.seq() == other.0.seq()
+        self.key == other.key
     }
 }
 
@@ -239,7 +1037,7 @@ impl PartialOrd for SeqPacket {
 
 impl Ord for SeqPacket {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.0.seq().cmp(&other.0.seq())
+        self.key.cmp(&other.key)
     }
 }
 
@@ -252,7 +1050,7 @@ mod tests {
     fn test_pkt() {
         let pkt1 = make_pkt_data(123);
         let _ = pkt1.decode();
-        assert_eq!(72, pkt1.data_len);
+        assert_eq!(72, pkt1.data_len());
         assert_eq!(62, pkt1.header.borrow().as_ref().unwrap().payload_offset);
         assert_eq!(10, pkt1.header.borrow().as_ref().unwrap().payload_len);
         assert_eq!(25, pkt1.header.borrow().as_ref().unwrap().sport());
@@ -277,18 +1075,18 @@ mod tests {
     fn test_seqpacket_eq() {
         let pkt1 = make_pkt_data(123);
         let _ = pkt1.decode();
-        let pkt1 = SeqPacket(pkt1);
+        let pkt1 = SeqPacket::new(pkt1, 0);
         let pkt2 = make_pkt_data(123);
         let _ = pkt2.decode();
-        let pkt2 = SeqPacket(pkt2);
+        let pkt2 = SeqPacket::new(pkt2, 0);
         assert_eq!(pkt1, pkt2);
 
         let pkt1 = make_pkt_data(123);        
         let _ = pkt1.decode();
-        let pkt1 = SeqPacket(pkt1);
+        let pkt1 = SeqPacket::new(pkt1, 0);
         let pkt2 = make_pkt_data(111);
         let _ = pkt2.decode();
-        let pkt2 = SeqPacket(pkt2);
+        let pkt2 = SeqPacket::new(pkt2, 0);
         assert_ne!(pkt1, pkt2);
         assert!(pkt1 > pkt2);
     }
@@ -297,26 +1095,26 @@ mod tests {
     fn test_seqpacket_ord() {
         let pkt1 = make_pkt_data(123);
         let _ = pkt1.decode();
-        let pkt1 = SeqPacket(pkt1);
+        let pkt1 = SeqPacket::new(pkt1, 0);
         let pkt2 = make_pkt_data(123);
         let _ = pkt2.decode();
-        let pkt2 = SeqPacket(pkt2);
+        let pkt2 = SeqPacket::new(pkt2, 0);
         assert!(pkt1 == pkt2);
 
         let pkt1 = make_pkt_data(123);
         let _ = pkt1.decode();
-        let pkt1 = SeqPacket(pkt1);
+        let pkt1 = SeqPacket::new(pkt1, 0);
         let pkt2 = make_pkt_data(111);
         let _ = pkt2.decode();
-        let pkt2 = SeqPacket(pkt2);
+        let pkt2 = SeqPacket::new(pkt2, 0);
         assert!(pkt1 > pkt2);
 
         let pkt1 = make_pkt_data(123);
         let _ = pkt1.decode();
-        let pkt1 = SeqPacket(pkt1);
+        let pkt1 = SeqPacket::new(pkt1, 0);
         let pkt2 = make_pkt_data(223);
         let _ = pkt2.decode();
-        let pkt2 = SeqPacket(pkt2);
+        let pkt2 = SeqPacket::new(pkt2, 0);
         assert!(pkt1 < pkt2);
     }
     
@@ -567,6 +1365,65 @@ mod tests {
         assert!(stm.is_empty());        
     }
     
+    #[test]
+    fn test_seq_cmp() {
+        assert!(seq_lt(1, 2));
+        assert!(!seq_lt(2, 2));
+        assert!(seq_le(2, 2));
+        assert!(seq_lt(u32::MAX, 0));
+        assert!(seq_lt(u32::MAX - 10, 10));
+        assert!(!seq_lt(10, u32::MAX - 10));
+    }
+
+    // 读了很远之后，排序基准跟着next_seq移动，回绕到基准之前的新包不会排到前面
+    #[test]
+    fn test_rebase() {
+        let mut stm = PktStrm::new();
+        let pkt1 = build_pkt(1, false);
+        let _ = pkt1.decode();
+        stm.push(pkt1);
+        assert_eq!(1, stm.pop_ord_data().unwrap().seq());
+
+        stm.next_seq = 0xf000_0001;
+        let pkt3 = build_pkt(0x1000_0001, false);
+        let _ = pkt3.decode();
+        stm.push(pkt3);
+        assert_eq!(0xf000_0001, stm.base);
+        let pkt2 = build_pkt(0xf000_0001, false);
+        let _ = pkt2.decode();
+        stm.push(pkt2);
+
+        assert_eq!(0xf000_0001, stm.pop_ord_data().unwrap().seq());
+        assert_eq!(None, stm.pop_ord_data());
+        assert_eq!(Some(1), stm.isn());
+    }
+
+    // 1-10 21-30，中间缺失。超时之后跳过缺失的部分，读完设置fin
+    #[test]
+    fn test_timeout() {
+        let mut stm = PktStrm::new();
+        assert!(!stm.timeout(DEFAULT_STRM_TIMEOUT * 2));
+
+        let pkt1 = build_pkt(1, false);
+        let _ = pkt1.decode();
+        let pkt3 = build_pkt(21, false);
+        let _ = pkt3.decode();
+        stm.push(pkt1);
+        stm.push(pkt3);
+
+        assert_eq!(1, stm.pop_ord_data().unwrap().seq());
+        assert_eq!(None, stm.pop_ord_data());
+        assert!(!stm.timeout(1 + DEFAULT_STRM_TIMEOUT));
+        assert!(!stm.fin);
+
+        assert!(stm.timeout(2 + DEFAULT_STRM_TIMEOUT));
+        assert!(stm.fin);
+        assert!(!stm.timeout(3 + DEFAULT_STRM_TIMEOUT));
+        assert_eq!(21, stm.pop_ord_data().unwrap().seq());
+        assert_eq!(31, stm.next_seq);
+        assert!(stm.is_empty());
+    }
+
     fn build_pkt(seq: u32, fin: bool) -> Rc<Packet> {
         //setup the packet headers
         let mut builder = PacketBuilder::
@@ -601,7 +1458,7 @@ mod tests {
         builder.write(&mut result, &payload).unwrap();
         // println!("result len:{}", result.len());
         
-        Packet::new(1, result.len(), &result)
+        Packet::new(1, result.len(), &result).unwrap()
     }
 
     fn make_pkt_data(seq: u32) -> Rc<Packet> {
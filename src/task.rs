@@ -1,12 +1,16 @@
 use core::{future::Future, pin::Pin, task::{Context, Poll, RawWaker, RawWakerVTable, Waker}};
 use futures_channel::mpsc;
+use etherparse::TransportHeader;
 use std::fmt;
 use crate::Packet;
+use crate::{CsumPolicy, CsumState};
 use std::rc::Rc;
 use crate::PktDirection;
 use crate::Parser;
-use crate::PktStrm;
+use crate::{PktStrm, OverlapPolicy, Anomaly, MemBudget, StrmStats};
 use crate::Meta;
+use crate::{FlowKey, is_server_port};
+use crate::{Conn, ConnState};
 
 const MAX_CHANNEL_SIZE: usize = 64;
 
@@ -20,6 +24,9 @@ pub struct Task {
     s2c_state: TaskState,
     bdir_state: TaskState,
     meta_rx: Option<mpsc::Receiver<Meta>>,
+    csum_policy: CsumPolicy,
+    client: Option<FlowKey>, // 客户端到服务器方向的key
+    conn: Conn,
 }
 
 impl Task {
@@ -37,6 +44,9 @@ impl Task {
             s2c_state: TaskState::Start,
             bdir_state: TaskState::Start,
             meta_rx: None,
+            csum_policy: CsumPolicy::default(),
+            client: None,
+            conn: Conn::default(),
         }
     }
     
@@ -60,6 +70,9 @@ impl Task {
             s2c_state: TaskState::Start,
             bdir_state: TaskState::Start,
             meta_rx: Some(rx),
+            csum_policy: CsumPolicy::default(),
+            client: None,
+            conn: Conn::default(),
         }
     }
 
@@ -77,23 +90,226 @@ impl Task {
         self.meta_rx = Some(rx);
     }
     
-    pub fn run(&mut self, pkt: Rc<Packet>, pkt_dir: PktDirection) {    
+    pub fn set_csum_policy(&mut self, policy: CsumPolicy) {
+        self.csum_policy = policy;
+    }
+
+    pub fn csum_policy(&self) -> CsumPolicy {
+        self.csum_policy
+    }
+
+    // 两个方向的流使用同样的超时时间
+    pub fn set_timeout(&mut self, timeout: u128) {
+        self.stream_c2s.set_timeout(timeout);
+        self.stream_s2c.set_timeout(timeout);
+    }
+
+    // 见PktStrm::set_midstream_wait
+    pub fn set_midstream_wait(&mut self, wait: u128) {
+        self.stream_c2s.set_midstream_wait(wait);
+        self.stream_s2c.set_midstream_wait(wait);
+    }
+
+    // 每个方向的流最多缓存的包数和载荷字节数
+    pub fn set_cache_limit(&mut self, max_pkts: usize, max_bytes: usize) {
+        for stream in [&mut self.stream_c2s, &mut self.stream_s2c] {
+            stream.set_max_pkts(max_pkts);
+            stream.set_max_bytes(max_bytes);
+        }
+    }
+
+    pub fn set_budget(&mut self, budget: Rc<MemBudget>) {
+        self.stream_c2s.set_budget(Rc::clone(&budget));
+        self.stream_s2c.set_budget(budget);
+    }
+
+    pub fn stats(&self, dir: PktDirection) -> StrmStats {
+        match dir {
+            PktDirection::Client2Server => self.stream_c2s.stats(),
+            PktDirection::Server2Client => self.stream_s2c.stats(),
+            _ => StrmStats::default(),
+        }
+    }
+
+    pub fn set_overlap_policy(&mut self, policy: OverlapPolicy) {
+        self.stream_c2s.set_overlap_policy(policy);
+        self.stream_s2c.set_overlap_policy(policy);
+    }
+
+    // 两个方向的流中发现的异常
+    pub fn get_anomaly(&mut self) -> Option<(PktDirection, Anomaly)> {
+        if let Some(anomaly) = self.stream_c2s.take_anomaly() {
+            return Some((PktDirection::Client2Server, anomaly));
+        }
+        self.stream_s2c.take_anomaly().map(|anomaly| (PktDirection::Server2Client, anomaly))
+    }
+
+    // 定期调用。空闲超时的流跳过缺失的数据并结束，阻塞在其上的解析器得以完成。
+    // 两个方向都空闲时整个连接超时，包括还没有收到过包的方向。
+    // 返回是否有流超时，或者结束了中途接入的等待
+    pub fn timeout(&mut self, now: u128) -> bool {
+        let before = (self.stream_c2s.is_expired(), self.stream_s2c.is_expired());
+        let waited = self.stream_c2s.timeout(now) | self.stream_s2c.timeout(now);
+        let idle = |stm: &PktStrm| stm.is_expired() || stm.last_ts().is_none();
+        let seen = self.stream_c2s.last_ts().is_some() || self.stream_s2c.last_ts().is_some();
+        if seen && idle(&self.stream_c2s) && idle(&self.stream_s2c) {
+            self.stream_c2s.expire();
+            self.stream_s2c.expire();
+        }
+        if !waited && before == (self.stream_c2s.is_expired(), self.stream_s2c.is_expired()) {
+            return false;
+        }
+
+        self.c2s_run();
+        self.s2c_run();
+        self.bdir_run();
+        true
+    }
+
+    // 连接不再跟踪时调用。两个方向的流都结束，解析器读完缓存中的数据
+    pub fn expire(&mut self) {
+        self.stream_c2s.expire();
+        self.stream_s2c.expire();
+        self.c2s_run();
+        self.s2c_run();
+        self.bdir_run();
+    }
+
+    // 客户端到服务器方向的key。还没有见到包时为None
+    pub fn client(&self) -> Option<FlowKey> {
+        self.client
+    }
+
+    // 方向为BiDirection或者Unknown的包，由task自己判断方向。借用模式的包只在本次调用中使用，缓存的是拷贝
+    pub fn run(&mut self, pkt: Rc<Packet>, pkt_dir: PktDirection) {
+        if pkt.check_csum(self.csum_policy).is_err() {
+            return;
+        }
+        self.run_pkt(pkt, pkt_dir);
+    }
+
+    fn run_pkt(&mut self, pkt: Rc<Packet>, pkt_dir: PktDirection) {
+        // 校验和错误被标记的包内容不可信，不参与方向判断，连接跟踪，确认号和重组
+        if pkt.header.borrow().as_ref().is_some_and(|header| header.csum == CsumState::Flagged) {
+            return;
+        }
+        let pkt_dir = match self.learn_dir(&pkt, pkt_dir) {
+            Some(dir) => dir,
+            None => return,
+        };
+        // 没有解析器再读的流不再缓存，释放内存预算。rst还要处理
+        let c2s_done = self.c2s_state != TaskState::Start && self.bdir_state != TaskState::Start;
+        let s2c_done = self.s2c_state != TaskState::Start && self.bdir_state != TaskState::Start;
+        let rst = pkt.rst() == Some(true);
+        let reset = self.is_reset();
+        let flushed = self.update_peer(&pkt, &pkt_dir);
+        self.conn.update(&pkt, &pkt_dir);
         match pkt_dir {
-            PktDirection::Client2Server => {
-                self.stream_c2s.push(pkt);
-                self.c2s_run();
-            }
-            PktDirection::Server2Client => {
-                self.stream_s2c.push(pkt);
-                self.s2c_run();
-            }
+            PktDirection::Client2Server if c2s_done && !rst => self.stream_c2s.discard(),
+            PktDirection::Server2Client if s2c_done && !rst => self.stream_s2c.discard(),
+            PktDirection::Client2Server => self.stream_c2s.push(pkt),
+            PktDirection::Server2Client => self.stream_s2c.push(pkt),
             _ => return
         }
+        if !reset && self.is_reset() {
+            self.reset();
+            return;
+        }
+        match pkt_dir {
+            PktDirection::Client2Server => self.c2s_run(),
+            _ => self.s2c_run(),
+        }
+        // 确认号让反方向跳过了丢失的数据，反方向的解析器也可以继续
+        if flushed {
+            match pkt_dir {
+                PktDirection::Client2Server => self.s2c_run(),
+                _ => self.c2s_run(),
+            }
+        }
         self.bdir_run();
     }
+
+    // 包的ack和窗口是反方向的流的对端信息。返回反方向是否因为确认号跳过了丢失的数据
+    fn update_peer(&mut self, pkt: &Packet, pkt_dir: &PktDirection) -> bool {
+        let header = pkt.header.borrow();
+        let tcph = match header.as_ref().and_then(|header| header.transport.as_ref()) {
+            Some(TransportHeader::Tcp(tcph)) if tcph.ack => tcph,
+            _ => return false,
+        };
+        let (own, peer) = match pkt_dir {
+            PktDirection::Client2Server => (&self.stream_c2s, &mut self.stream_s2c),
+            PktDirection::Server2Client => (&self.stream_s2c, &mut self.stream_c2s),
+            _ => return false,
+        };
+        // 两端的syn都带了扩大因子才生效(rfc7323)，syn本身的窗口不扩大
+        let shift = match (own.wscale(), peer.wscale()) {
+            (Some(shift), Some(_)) if !tcph.syn => shift,
+            _ => 0,
+        };
+        peer.update_peer(tcph.acknowledgment_number, (tcph.window_size as u32) << shift)
+    }
+
+    // 有效的rst结束两个方向的流，解析器读完缓存中的数据。因此结束的解析器状态为Reset
+    fn reset(&mut self) {
+        self.conn.reset();
+        self.stream_c2s.reset();
+        self.stream_s2c.reset();
+        let before = [self.c2s_state, self.s2c_state, self.bdir_state];
+        self.c2s_run();
+        self.s2c_run();
+        self.bdir_run();
+        for (state, before) in [&mut self.c2s_state, &mut self.s2c_state, &mut self.bdir_state].into_iter().zip(before) {
+            if before == TaskState::Start {
+                *state = TaskState::Reset;
+            }
+        }
+    }
+
+    // 连接是否被rst重置
+    pub fn is_reset(&self) -> bool {
+        self.stream_c2s.is_rst() || self.stream_s2c.is_rst()
+    }
+
+    pub fn conn_state(&self) -> ConnState {
+        self.conn.state()
+    }
+
+    // 握手中得到的初始序号。客户端的也可以从syn+ack的确认号得到
+    pub fn isn(&self, dir: PktDirection) -> Option<u32> {
+        self.conn.isn(&dir)
+    }
+
+    // 没有见到握手，从连接中途开始
+    pub fn is_midstream(&self) -> bool {
+        self.conn.is_midstream()
+    }
+
+    // 握手没有完成就被rst，一般是服务器拒绝了连接
+    pub fn is_rejected(&self) -> bool {
+        self.conn.is_rejected()
+    }
     
+    // 第一个包确定客户端，之后按客户端判断方向。不属于这个连接的包返回None
+    fn learn_dir(&mut self, pkt: &Packet, pkt_dir: PktDirection) -> Option<PktDirection> {
+        let key = match FlowKey::from_pkt(pkt) {
+            Some(key) => key,
+            None => return Some(pkt_dir),
+        };
+        let client = *self.client.get_or_insert_with(|| match pkt_dir {
+            PktDirection::Client2Server => key,
+            PktDirection::Server2Client => key.reverse(),
+            _ => guess_client(pkt, &key),
+        });
+        match pkt_dir {
+            PktDirection::Client2Server | PktDirection::Server2Client => Some(pkt_dir),
+            _ if key == client => Some(PktDirection::Client2Server),
+            _ if key == client.reverse() => Some(PktDirection::Server2Client),
+            _ => None,
+        }
+    }
+
     fn c2s_run(&mut self) {
-        if self.c2s_state == TaskState::End {
+        if self.c2s_state != TaskState::Start {
             return;
         }
 
@@ -108,7 +324,7 @@ impl Task {
     }
 
     fn s2c_run(&mut self) {
-        if self.s2c_state == TaskState::End {
+        if self.s2c_state != TaskState::Start {
             return;
         }
 
@@ -123,7 +339,7 @@ impl Task {
     }
     
     fn bdir_run(&mut self) {
-        if self.bdir_state == TaskState::End {
+        if self.bdir_state != TaskState::Start {
             return;
         }
 
@@ -141,15 +357,17 @@ impl Task {
         self.meta_rx.as_ref()?;
 
         if let Some(rx) = self.meta_rx.as_mut() {
-            match rx.try_next() {
-                Ok(meta) => meta,
-                _ => None
-            }
+            rx.try_recv().ok()
         } else {
             None
         }
     }
     
+    // 三个方向的解析器都已经结束，不会再产生meta
+    pub fn is_finished(&self) -> bool {
+        [self.c2s_state, self.s2c_state, self.bdir_state].iter().all(|state| *state != TaskState::Start)
+    }
+
     pub fn parser_state(&self, dir: PktDirection) -> TaskState {
         match dir {
             PktDirection::Client2Server => self.c2s_state,
@@ -192,9 +410,21 @@ impl fmt::Debug for Task {
 pub enum TaskState {
     Start,
     End,
+    Reset, // 连接被rst重置，解析器因此结束
     Error
 }
 
+// 依次根据syn，服务端口，谁先发包来判断客户端
+fn guess_client(pkt: &Packet, key: &FlowKey) -> FlowKey {
+    if pkt.syn() {
+        return if pkt.ack() == Some(true) { key.reverse() } else { *key };
+    }
+    match (is_server_port(key.sport), is_server_port(key.dport)) {
+        (true, false) => key.reverse(),
+        _ => *key,
+    }
+}
+
 fn dummy_raw_waker() -> RawWaker {
     fn no_op(_: *const ()) {}
     fn clone(_: *const ()) -> RawWaker {
@@ -292,6 +522,6 @@ mod tests {
         builder.write(&mut result, &payload).unwrap();
         // println!("result len:{}", result.len());
         
-        Packet::new(1, result.len(), &result)
+        Packet::new(1, result.len(), &result).unwrap()
     }
 }
@@ -0,0 +1,317 @@
+use etherparse::{Ethernet2Header, SingleVlanHeader, DoubleVlanHeader, VlanHeader, IpHeader, TcpHeader, UdpHeader,
+                 TransportHeader, TcpOptionReadError, ReadError, SerializedSize, TCP_MINIMUM_HEADER_SIZE,
+                 ether_type, ip_number};
+use crate::{LinkType, PacketError};
+
+// 最多解几层隧道，超过的包解码失败
+pub const MAX_TUNNEL_DEPTH: usize = 4;
+
+const IP_GRE: u8 = 47;
+const ETHER_MPLS: u16 = 0x8847;
+const ETHER_MPLS_MCAST: u16 = 0x8848;
+const ETHER_TEB: u16 = 0x6558;     // transparent ethernet bridging，内层是以太网
+const ETHER_ERSPAN2: u16 = 0x88be;
+const PORT_VXLAN: u16 = 4789;
+const PORT_GENEVE: u16 = 6081;
+const PORT_GTPU: u16 = 2152;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TunnelType {
+    Gre,
+    Vxlan,
+    Geneve,
+    GtpU,
+    Mpls,
+}
+
+// 一层隧道的外层头。mpls直接在链路层之上，ip为None
+#[derive(Eq, PartialEq, Clone)]
+pub struct Tunnel {
+    pub tunnel_type: TunnelType,
+    pub id: Option<u32>, // gre的key，vxlan和geneve的vni，gtp-u的teid，mpls栈底的label
+    pub link: Option<Ethernet2Header>,
+    pub vlan: Option<VlanHeader>,
+    pub ip: Option<IpHeader>,
+}
+
+// 下一层从哪里开始解析
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Layer {
+    Link(LinkType),
+    Ether(u16),
+}
+
+// 一层解析的结果
+pub(crate) struct Headers<'a> {
+    pub link: Option<Ethernet2Header>,
+    pub vlan: Option<VlanHeader>,
+    pub ether: u16, // 链路层和vlan之后的ether type
+    pub ip: Option<IpHeader>,
+    pub transport: Option<TransportHeader>,
+    pub fragment: bool,
+    pub payload: &'a [u8],
+    pub offset: usize, // payload在整个帧中的位置
+}
+
+impl Layer {
+    // 解析链路层，vlan，ip和tcp/udp。base是data在整个帧中的位置，用来给出出错的偏移。
+    // ip之后不是tcp/udp，或者链路层之后不是ip，都不算错，由调用者判断
+    pub(crate) fn decode(self, data: &[u8], base: usize) -> Result<Headers<'_>, PacketError> {
+        let truncated = PacketError::Truncated { offset: base + data.len() };
+        let mut link = None;
+        let (mut pos, mut ether) = match self {
+            Layer::Link(LinkType::Ethernet) => {
+                let (eth, _) = Ethernet2Header::from_slice(data).map_err(|_| truncated)?;
+                let ether = eth.ether_type;
+                link = Some(eth);
+                (Ethernet2Header::SERIALIZED_SIZE, ether)
+            }
+            Layer::Link(link_type) => match link_type.network(data) {
+                Some(network) => network,
+                None if data.len() < link_type.header_len() => return Err(truncated),
+                None => return Err(PacketError::NonIp { offset: base }),
+            },
+            Layer::Ether(ether) => (0, ether),
+        };
+
+        let mut vlans = Vec::new();
+        while matches!(ether, ether_type::VLAN_TAGGED_FRAME | ether_type::PROVIDER_BRIDGING | ether_type::VLAN_DOUBLE_TAGGED_FRAME) {
+            if vlans.len() == 2 {
+                return Err(PacketError::UnsupportedEtherType { offset: base + pos, ether_type: ether });
+            }
+            let (vlan, _) = SingleVlanHeader::from_slice(&data[pos..]).map_err(|_| truncated)?;
+            ether = vlan.ether_type;
+            pos += SingleVlanHeader::SERIALIZED_SIZE;
+            vlans.push(vlan);
+        }
+        let vlan = match (vlans.pop(), vlans.pop()) {
+            (Some(inner), Some(outer)) => Some(VlanHeader::Double(DoubleVlanHeader { outer, inner })),
+            (Some(vlan), None) => Some(VlanHeader::Single(vlan)),
+            _ => None,
+        };
+
+        let mut headers = Headers {
+            link,
+            vlan,
+            ether,
+            ip: None,
+            transport: None,
+            fragment: false,
+            payload: &data[pos..],
+            offset: base + pos,
+        };
+        if !matches!(ether, ether_type::IPV4 | ether_type::IPV6) {
+            return Ok(headers);
+        }
+
+        let (ip, proto, rest) = IpHeader::from_slice(&data[pos..]).map_err(|err| match err {
+            ReadError::UnexpectedEndOfSlice(_) => truncated,
+            ReadError::IpUnsupportedVersion(_) | ReadError::Ipv4UnexpectedVersion(_) | ReadError::Ipv6UnexpectedVersion(_) => {
+                PacketError::NonIp { offset: base + pos }
+            }
+            _ => PacketError::MalformedOptions { offset: base + pos },
+        })?;
+        pos = data.len() - rest.len();
+        headers.fragment = is_fragment(&ip);
+        headers.ip = Some(ip);
+        headers.payload = rest;
+        headers.offset = base + pos;
+        if headers.fragment {
+            return Ok(headers);
+        }
+
+        let (transport, rest) = match proto {
+            ip_number::TCP => {
+                let (tcph, rest) = TcpHeader::from_slice(rest).map_err(|err| match err {
+                    ReadError::UnexpectedEndOfSlice(_) => truncated,
+                    _ => PacketError::MalformedOptions { offset: base + pos },
+                })?;
+                // 未知的选项不算错误
+                let malformed = tcph.options_iterator().any(|opt| {
+                    matches!(opt, Err(TcpOptionReadError::UnexpectedEndOfSlice { .. } | TcpOptionReadError::UnexpectedSize { .. }))
+                });
+                if malformed {
+                    return Err(PacketError::MalformedOptions { offset: base + pos + TCP_MINIMUM_HEADER_SIZE });
+                }
+                (TransportHeader::Tcp(tcph), rest)
+            }
+            ip_number::UDP => {
+                let (udph, rest) = UdpHeader::from_slice(rest).map_err(|_| truncated)?;
+                (TransportHeader::Udp(udph), rest)
+            }
+            _ => return Ok(headers),
+        };
+        headers.transport = Some(transport);
+        headers.payload = rest;
+        headers.offset = base + data.len() - rest.len();
+        Ok(headers)
+    }
+}
+
+// headers是一层隧道的外层时，返回隧道，内层在headers.payload中的位置，以及内层从哪里开始解析
+pub(crate) fn decap(headers: &Headers) -> Option<(Tunnel, usize, Layer)> {
+    let payload = headers.payload;
+    let (tunnel_type, id, offset, layer) = match (&headers.ip, &headers.transport) {
+        (None, None) => {
+            if !matches!(headers.ether, ETHER_MPLS | ETHER_MPLS_MCAST) {
+                return None;
+            }
+            let (id, offset, layer) = mpls(payload)?;
+            (TunnelType::Mpls, id, offset, layer)
+        }
+        (Some(ip), None) => {
+            if headers.fragment || ip.next_header().ok()? != IP_GRE {
+                return None;
+            }
+            let (id, offset, layer) = gre(payload)?;
+            (TunnelType::Gre, id, offset, layer)
+        }
+        (Some(_), Some(TransportHeader::Udp(udph))) => {
+            if udph.destination_port == PORT_VXLAN {
+                let (id, offset, layer) = vxlan(payload)?;
+                (TunnelType::Vxlan, id, offset, layer)
+            } else if udph.destination_port == PORT_GENEVE {
+                let (id, offset, layer) = geneve(payload)?;
+                (TunnelType::Geneve, id, offset, layer)
+            } else if udph.destination_port == PORT_GTPU || udph.source_port == PORT_GTPU {
+                let (id, offset, layer) = gtpu(payload)?;
+                (TunnelType::GtpU, id, offset, layer)
+            } else {
+                return None;
+            }
+        }
+        _ => return None,
+    };
+
+    let tunnel = Tunnel {
+        tunnel_type,
+        id,
+        link: headers.link.clone(),
+        vlan: headers.vlan.clone(),
+        ip: headers.ip.clone(),
+    };
+    Some((tunnel, offset, layer))
+}
+
+fn is_fragment(ip: &IpHeader) -> bool {
+    match ip {
+        IpHeader::Version4(ipv4h, _) => ipv4h.is_fragmenting_payload(),
+        IpHeader::Version6(_, ext) => ext.is_fragmenting_payload(),
+    }
+}
+
+fn be32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn ether_layer(proto: u16) -> Layer {
+    if proto == ETHER_TEB {
+        Layer::Link(LinkType::Ethernet)
+    } else {
+        Layer::Ether(proto)
+    }
+}
+
+// 根据版本号判断内层是ipv4还是ipv6
+fn ip_layer(data: &[u8], offset: usize) -> Option<Layer> {
+    match data.get(offset)? >> 4 {
+        4 => Some(Layer::Ether(ether_type::IPV4)),
+        6 => Some(Layer::Ether(ether_type::IPV6)),
+        _ => None,
+    }
+}
+
+// rfc2784, rfc2890。只支持版本0，包括erspan type II
+fn gre(data: &[u8]) -> Option<(Option<u32>, usize, Layer)> {
+    let head = data.get(0..4)?;
+    if head[1] & 0x07 != 0 {
+        return None;
+    }
+    let csum = head[0] & 0x80 != 0;
+    let key = head[0] & 0x20 != 0;
+    let seq = head[0] & 0x10 != 0;
+    let proto = u16::from_be_bytes([head[2], head[3]]);
+
+    let mut offset = 4;
+    if csum {
+        offset += 4;
+    }
+    let id = if key {
+        offset += 4;
+        Some(be32(data, offset - 4)?)
+    } else {
+        None
+    };
+    if seq {
+        offset += 4;
+    }
+
+    match proto {
+        ETHER_ERSPAN2 => Some((id, offset + 8, Layer::Link(LinkType::Ethernet))),
+        ether_type::IPV4 | ether_type::IPV6 | ETHER_MPLS | ETHER_MPLS_MCAST | ETHER_TEB => {
+            Some((id, offset, ether_layer(proto)))
+        }
+        _ => None,
+    }
+}
+
+// rfc7348
+fn vxlan(data: &[u8]) -> Option<(Option<u32>, usize, Layer)> {
+    let flags = *data.first()?;
+    if flags & 0x08 == 0 {
+        return None;
+    }
+    Some((Some(be32(data, 4)? >> 8), 8, Layer::Link(LinkType::Ethernet)))
+}
+
+// rfc8926
+fn geneve(data: &[u8]) -> Option<(Option<u32>, usize, Layer)> {
+    let head = data.get(0..4)?;
+    if head[0] >> 6 != 0 {
+        return None;
+    }
+    let offset = 8 + (head[0] & 0x3f) as usize * 4;
+    let proto = u16::from_be_bytes([head[2], head[3]]);
+    Some((Some(be32(data, 4)? >> 8), offset, ether_layer(proto)))
+}
+
+// 3gpp ts 29.281。只解G-PDU，跳过可选字段和扩展头
+fn gtpu(data: &[u8]) -> Option<(Option<u32>, usize, Layer)> {
+    let head = data.get(0..2)?;
+    if head[0] >> 5 != 1 || head[0] & 0x10 == 0 || head[1] != 0xff {
+        return None;
+    }
+    let teid = be32(data, 4)?;
+
+    let mut offset = 8;
+    if head[0] & 0x07 != 0 {
+        offset = 12;
+        let mut next = if head[0] & 0x04 != 0 { *data.get(11)? } else { 0 };
+        while next != 0 {
+            let len = *data.get(offset)? as usize * 4;
+            if len == 0 {
+                return None;
+            }
+            next = *data.get(offset + len - 1)?;
+            offset += len;
+        }
+    }
+    Some((Some(teid), offset, ip_layer(data, offset)?))
+}
+
+// rfc3032。label栈之后根据版本号判断ip，0表示pseudowire的control word，之后是以太网
+fn mpls(data: &[u8]) -> Option<(Option<u32>, usize, Layer)> {
+    let mut offset = 0;
+    loop {
+        let entry = be32(data, offset)?;
+        offset += 4;
+        if entry & 0x100 != 0 {
+            let label = entry >> 12;
+            return match data.get(offset)? >> 4 {
+                0 => Some((Some(label), offset + 4, Layer::Link(LinkType::Ethernet))),
+                _ => Some((Some(label), offset, ip_layer(data, offset)?)),
+            };
+        }
+    }
+}
@@ -0,0 +1,127 @@
+mod common;
+
+use futures::executor::block_on;
+use memerge::*;
+use crate::common::*;
+
+// 对端确认了缺失的数据，缺失马上被跳过。部分确认的缺失合并成一个
+#[test]
+fn test_ack_lost() {
+    let mut stm = PktStrm::new();
+    stm.push(c2s(101, 1001, "AP", b"HEL"));
+    stm.push(c2s(110, 1001, "AP", b"QUIT\r\n"));
+    assert_eq!(b"HEL".to_vec(), block_on(stm.readn(3)));
+
+    // 确认号没有超过已经读到的位置
+    assert!(!stm.update_peer(104, 1024));
+    assert!(!stm.has_gap());
+    assert!(stm.update_peer(107, 1024));
+    assert!(stm.update_peer(112, 1024));
+    assert_eq!(Some(Gap { seq: 104, len: 6 }), stm.take_gap());
+    assert_eq!(1, stm.stats().gaps);
+    assert_eq!(6, stm.stats().gap_bytes);
+    assert_eq!("QUIT\r\n", block_on(stm.readline()).unwrap());
+
+    // 缓存中没有数据时，等到下一次timeout才跳到确认号
+    assert!(!stm.update_peer(120, 1024));
+    assert!(!stm.has_gap());
+    assert!(stm.timeout(2));
+    assert_eq!(Some(Gap { seq: 116, len: 4 }), stm.take_gap());
+    assert!(!stm.timeout(3));
+
+    // fin占用的序号不算缺失
+    stm.push(c2s(120, 1001, "AF", b"BYE"));
+    stm.expire();
+    assert_eq!(b"BYE".to_vec(), block_on(stm.readn(10)));
+    assert!(!stm.update_peer(124, 1024));
+    assert!(!stm.has_gap());
+}
+
+// 抓包点乱序，确认先于被确认的数据到达，数据到了就不是缺失
+#[test]
+fn test_ack_before_data() {
+    let mut stm = PktStrm::new();
+    stm.push(c2s(101, 1001, "AP", b"HEL"));
+    assert_eq!(b"HEL".to_vec(), block_on(stm.readn(3)));
+
+    assert!(!stm.update_peer(110, 1024));
+    assert!(!stm.has_gap());
+    stm.push(c2s(104, 1001, "AP", b"LO\r\n"));
+    stm.push(c2s(108, 1001, "AP", b"Q\n"));
+    assert!(!stm.timeout(2));
+    assert!(!stm.has_gap());
+    assert_eq!(b"LO\r\nQ\n".to_vec(), block_on(stm.readn(6)));
+    assert_eq!(0, stm.stats().gaps);
+}
+
+// 服务器的确认号让阻塞在缺失上的客户端解析器继续，不用等超时
+#[test] #[cfg(not(miri))]
+fn test_ack_flush() {
+    let mut task = Task::new_with_parser(LinesParser);
+    task.run(c2s(100, 0, "S", &[]), PktDirection::Unknown);
+    task.run(s2c(1000, 101, "SA", &[]), PktDirection::Unknown);
+    task.run(c2s(101, 1001, "AP", b"HEL"), PktDirection::Unknown);
+    task.run(c2s(110, 1001, "AP", b"QUIT\r\n"), PktDirection::Unknown);
+    assert!(lines(&mut task).is_empty());
+
+    task.run(s2c(1001, 104, "A", &[]), PktDirection::Unknown);
+    assert!(lines(&mut task).is_empty());
+    task.run(s2c(1001, 116, "A", &[]), PktDirection::Unknown);
+    assert_eq!(vec!["HEL", "QUIT\r\n"], lines(&mut task));
+    assert_eq!(1, task.stats(PktDirection::Client2Server).gaps);
+    assert_eq!(TaskState::Start, task.parser_state(PktDirection::Client2Server));
+}
+
+// 确认先到时解析器继续等数据。数据没有到的话，下一次timeout跳过缺失
+#[test] #[cfg(not(miri))]
+fn test_ack_flush_timeout() {
+    let mut task = Task::new_with_parser(LinesParser);
+    task.run(c2s(100, 0, "S", &[]), PktDirection::Unknown);
+    task.run(s2c(1000, 101, "SA", &[]), PktDirection::Unknown);
+    task.run(c2s(101, 1001, "AP", b"HEL"), PktDirection::Unknown);
+    task.run(s2c(1001, 110, "A", &[]), PktDirection::Unknown);
+    assert!(lines(&mut task).is_empty());
+
+    assert!(task.timeout(2));
+    assert_eq!(vec!["HEL"], lines(&mut task));
+    task.run(c2s(110, 1001, "AP", b"QUIT\r\n"), PktDirection::Unknown);
+    assert_eq!(vec!["QUIT\r\n"], lines(&mut task));
+    assert_eq!(1, task.stats(PktDirection::Client2Server).gaps);
+}
+
+// 校验和错误的确认号可能是伪造的，不能让另一个方向跳过还没有到达的数据
+#[test] #[cfg(not(miri))]
+fn test_ack_flagged() {
+    let mut task = Task::new_with_parser(LinesParser);
+    task.set_csum_policy(CsumPolicy::Flag);
+    task.run(c2s(100, 0, "S", &[]), PktDirection::Unknown);
+    task.run(s2c(1000, 101, "SA", &[]), PktDirection::Unknown);
+    task.run(c2s(101, 1001, "AP", b"HELO\r\n"), PktDirection::Unknown);
+    task.run(c2s(117, 1001, "AP", b"QUIT\r\n"), PktDirection::Unknown);
+    assert_eq!(vec!["HELO\r\n"], lines(&mut task));
+
+    let forged = corrupt(&s2c(1001, 123, "A", &[]), TCP_CSUM);
+    forged.decode().unwrap();
+    task.run(forged, PktDirection::Unknown);
+    assert!(!task.timeout(2));
+    task.run(c2s(107, 1001, "AP", b"MAIL FRM\r\n"), PktDirection::Unknown);
+    assert_eq!(vec!["MAIL FRM\r\n", "QUIT\r\n"], lines(&mut task));
+    assert_eq!(0, task.stats(PktDirection::Client2Server).gaps);
+}
+
+// 解析器结束之后流不再缓存，读的位置不再前进。之后的确认号不算缺失
+#[test] #[cfg(not(miri))]
+fn test_ack_discarded() {
+    let mut task = Task::new_with_parser(LineParser);
+    task.run(c2s(100, 0, "S", &[]), PktDirection::Unknown);
+    task.run(s2c(1000, 101, "SA", &[]), PktDirection::Unknown);
+    task.run(c2s(101, 1001, "AP", b"HELO\r\n"), PktDirection::Unknown);
+    assert_eq!(vec!["HELO\r\n"], lines(&mut task));
+    assert_eq!(TaskState::End, task.parser_state(PktDirection::Client2Server));
+
+    task.run(c2s(107, 1001, "AP", &[b'a'; 1000]), PktDirection::Unknown);
+    task.run(s2c(1001, 1107, "A", &[]), PktDirection::Unknown);
+    task.timeout(2);
+    assert_eq!(0, task.stats(PktDirection::Client2Server).gaps);
+    assert_eq!(0, task.stats(PktDirection::Client2Server).gap_bytes);
+}
@@ -0,0 +1,136 @@
+mod common;
+
+use core::{future::Future, pin::Pin};
+use futures_channel::mpsc;
+use memerge::*;
+use std::rc::Rc;
+use crate::common::*;
+
+fn data(seq: u32) -> Rc<Packet> {
+    let pkt = build_pkt(seq, false);
+    let _ = pkt.decode();
+    pkt
+}
+
+// 包数限制：先跳过挡住读取的缺失，再淘汰下一个缺失之后最远的包
+#[test]
+fn test_cache_max_pkts() {
+    let mut stm = PktStrm::new();
+    stm.set_max_pkts(4);
+    stm.push(data(1));
+    assert_eq!(1, stm.pop_ord_data().unwrap().seq());
+
+    for seq in [21, 31, 51, 71] {
+        stm.push(data(seq));
+    }
+    stm.push(data(41));
+    assert_eq!(4, stm.len());
+    assert_eq!(Some(Gap { seq: 11, len: 10 }), stm.take_gap());
+    assert_eq!(StrmStats { dropped_pkts: 1, dropped_bytes: 10, gaps: 1, gap_bytes: 10 }, stm.stats());
+
+    let seqs: Vec<u32> = std::iter::from_fn(|| stm.pop_ord_data()).map(|pkt| pkt.seq()).collect();
+    assert_eq!(vec![21, 31, 41, 51], seqs);
+    assert!(stm.is_empty());
+}
+
+// 字节数限制：新包比缓存中的都远时丢弃新包
+#[test]
+fn test_cache_max_bytes() {
+    let mut stm = PktStrm::new();
+    stm.set_max_bytes(25);
+    stm.push(data(1));
+    stm.push(data(21));
+    assert_eq!(20, stm.bytes());
+
+    stm.push(data(41));
+    assert_eq!(2, stm.len());
+    assert_eq!(1, stm.stats().dropped_pkts);
+
+    // 离得更近的包淘汰最远的
+    stm.push(data(11));
+    assert_eq!(2, stm.len());
+    assert_eq!(20, stm.bytes());
+    assert_eq!(2, stm.stats().dropped_pkts);
+    assert_eq!(1, stm.pop_ord_data().unwrap().seq());
+    assert_eq!(11, stm.pop_ord_data().unwrap().seq());
+    assert_eq!(0, stm.bytes());
+}
+
+// 多个流共用预算，读走或者释放流之后归还
+#[test]
+fn test_cache_budget() {
+    let budget = Rc::new(MemBudget::new(30));
+    let mut stm1 = PktStrm::new();
+    stm1.set_budget(Rc::clone(&budget));
+    let mut stm2 = PktStrm::new();
+    stm2.set_budget(Rc::clone(&budget));
+
+    stm1.push(data(1));
+    stm1.push(data(21));
+    stm2.push(data(1));
+    assert_eq!(30, budget.used());
+    stm2.push(data(11));
+    assert_eq!(1, stm2.stats().dropped_pkts);
+    assert_eq!(1, stm2.len());
+
+    assert_eq!(1, stm1.pop_ord_data().unwrap().seq());
+    assert_eq!(20, budget.used());
+    stm2.push(data(11));
+    assert_eq!(2, stm2.len());
+    assert_eq!(30, budget.used());
+
+    drop(stm1);
+    assert_eq!(20, budget.used());
+    stm2.clear();
+    assert_eq!(0, budget.used());
+}
+
+// 解析器都已经结束的流不再缓存
+#[test] #[cfg(not(miri))]
+fn test_cache_parser_end() {
+    struct C2sOnly;
+    impl Parser for C2sOnly {
+        fn c2s_parser(&self, stream: *const PktStrm, _meta_tx: mpsc::Sender<Meta>) -> Pin<Box<dyn Future<Output = ()>>> {
+            Box::pin(async move {
+                let stm: &mut PktStrm;
+                unsafe { stm = &mut *(stream as *mut PktStrm); }
+                while stm.readline().await.unwrap() != "" {}
+            })
+        }
+    }
+
+    let budget = Rc::new(MemBudget::new(1000));
+    let mut task = Task::new_with_parser(C2sOnly);
+    task.set_budget(Rc::clone(&budget));
+    task.run(data(1), PktDirection::Server2Client);
+    task.run(data(21), PktDirection::Server2Client);
+    assert_eq!(0, task.steeam_len(PktDirection::Server2Client));
+    task.run(data(21), PktDirection::Client2Server);
+    task.run(data(41), PktDirection::Client2Server);
+    assert_eq!(1, task.steeam_len(PktDirection::Client2Server));
+    assert_eq!(10, budget.used());
+}
+
+// 流表中的连接共用预算，连接删除后归还
+#[test] #[cfg(not(miri))]
+fn test_cache_flow_table() {
+    let mut flow_table = FlowTable::new(|_| Some(Box::new(DummyParser)));
+    flow_table.set_mem_budget(15);
+    flow_table.set_cache_limit(8, 1000);
+    let client = ([192,168,1,2], 4000);
+    let server = ([192,168,1,1], 25);
+    flow_table.run(build_tcp(client, server, 1, 0, "A", b"0123456789")).unwrap();
+    flow_table.run(build_tcp(client, server, 21, 0, "A", b"0123456789")).unwrap();
+    assert_eq!(10, flow_table.mem_budget().unwrap().used());
+
+    let key = flow_table.run(build_tcp(server, client, 1, 0, "R", &[])).unwrap().unwrap();
+    assert!(flow_table.task(&key).is_none());
+    assert_eq!(0, flow_table.mem_budget().unwrap().used());
+}
+
+struct DummyParser;
+impl Parser for DummyParser {
+    fn bdir_parser(&self, _c2s: *const PktStrm, _s2c: *const PktStrm, _meta_tx: mpsc::Sender<Meta>) -> Pin<Box<dyn Future<Output = ()>>> {
+        Box::pin(futures::future::pending())
+    }
+}
@@ -1,7 +1,11 @@
 #![allow(unused)]
 
 use etherparse::*;
+use futures_channel::mpsc;
+use futures_util::SinkExt;
+use core::{future::Future, pin::Pin};
 use memerge::*;
+use memerge::smtp::MetaSmtp;
 use std::rc::Rc;
 use pcap::Capture as PcapCap;
 use pcap::Offline;
@@ -13,13 +17,16 @@ pub enum CaptureError {
 
 pub struct Capture {
     cap: PcapCap<Offline>,
+    link_type: LinkType,
     pkt_num: u64
 }
 
 impl Capture {
     pub fn init<P: AsRef<Path>>(path: P) -> Result<Capture, CaptureError> {
+        let cap = PcapCap::from_file(path).unwrap();
         let capture = Capture {
-            cap: PcapCap::from_file(path).unwrap(),
+            link_type: LinkType::from_dlt(cap.get_datalink().0).unwrap(),
+            cap,
             pkt_num: 0
         };
         Ok(capture)
@@ -29,11 +36,7 @@ impl Capture {
         self.pkt_num += 1;
         match self.cap.next_packet() {
             Ok(pcap_pkt) => {
-                if pcap_pkt.header.caplen > MAX_PACKET_LEN.try_into().unwrap() {
-                    return None;
-                }
-
-                Some(Packet::new(timestamp, pcap_pkt.header.caplen.try_into().unwrap(), pcap_pkt.data))
+                Packet::new_with_link(timestamp, pcap_pkt.header.caplen.try_into().unwrap(), pcap_pkt.data, self.link_type).ok()
             }
             Err(_) => None,
         }
@@ -74,7 +77,7 @@ pub fn build_pkt_nodata(seq: u32, fin: bool) -> Rc<Packet> {
     builder.write(&mut result, &payload).unwrap();
     // println!("result len:{}", result.len());
     
-    Packet::new(1, result.len(), &result)
+    Packet::new(1, result.len(), &result).unwrap()
 }
 
 // 独立的ack包，没有载荷
@@ -109,7 +112,7 @@ pub fn build_pkt_ack(seq: u32, ack_seq: u32) -> Rc<Packet> {
     builder.write(&mut result, &payload).unwrap();
     // println!("result len:{}", result.len());
     
-    Packet::new(1, result.len(), &result)
+    Packet::new(1, result.len(), &result).unwrap()
 }
 
 // 独立的syn包，没有载荷
@@ -144,7 +147,7 @@ pub fn build_pkt_syn(seq: u32) -> Rc<Packet> {
     builder.write(&mut result, &payload).unwrap();
     // println!("result len:{}", result.len());
     
-    Packet::new(1, result.len(), &result)
+    Packet::new(1, result.len(), &result).unwrap()
 }
 
 pub fn make_pkt_data(seq: u32) -> Rc<Packet> {
@@ -179,7 +182,7 @@ pub fn build_pkt_line(seq: u32, payload: [u8;10]) -> Rc<Packet> {
     //this will automatically set all length fields, checksums and identifiers (ethertype & protocol)
     builder.write(&mut result, &payload).unwrap();
     
-    Packet::new(1, result.len(), &result)
+    Packet::new(1, result.len(), &result).unwrap()
 }
 
 // 带载荷，可以带fin
@@ -217,7 +220,7 @@ pub fn build_pkt(seq: u32, fin: bool) -> Rc<Packet> {
     builder.write(&mut result, &payload).unwrap();
     // println!("result len:{}", result.len());
     
-    Packet::new(1, result.len(), &result)
+    Packet::new(1, result.len(), &result).unwrap()
 }
 
 // 独立的fin包，没有载荷
@@ -225,3 +228,198 @@ pub fn build_pkt_fin(seq: u32) -> Rc<Packet> {
     build_pkt_nodata(seq, true)
 }
 
+
+pub const IPV6_SRC: [u8;16] = [0x20,0x01,0x0d,0xb8,0,0,0,0,0,0,0,0,0,0,0,1];
+pub const IPV6_DST: [u8;16] = [0x20,0x01,0x0d,0xb8,0,0,0,0,0,0,0,0,0,0,0,2];
+
+// ipv6的包，可以带ipv6扩展头
+pub fn build_pkt6_ext(seq: u32, syn: bool, fin: bool, ext: Ipv6Extensions, payload: &[u8]) -> Rc<Packet> {
+    let ip = IpHeader::Version6(Ipv6Header {
+        traffic_class: 0,
+        flow_label: 0,
+        payload_length: 0,
+        next_header: 0,
+        hop_limit: 64,
+        source: IPV6_SRC,
+        destination: IPV6_DST,
+    }, ext);
+    let mut builder = PacketBuilder::
+    ethernet2([1,2,3,4,5,6],     //source mac
+              [7,8,9,10,11,12]) //destionation mac
+        .ip(ip)
+        .tcp(25,    //source port 
+             4000,  //desitnation port
+             seq,     //sequence number
+             1024); //window size
+    if syn {
+        builder = builder.syn();
+    } else {
+        builder = builder.ack(123);
+    }
+    if fin {
+        builder = builder.fin();
+    }
+
+    let mut result = Vec::<u8>::with_capacity(builder.size(payload.len()));
+    builder.write(&mut result, payload).unwrap();
+    
+    Packet::new(1, result.len(), &result).unwrap()
+}
+
+// ipv6的包，带载荷，可以带fin
+pub fn build_pkt6(seq: u32, fin: bool) -> Rc<Packet> {
+    build_pkt6_ext(seq, false, fin, Default::default(), &[1,2,3,4,5,6,7,8,9,10])
+}
+
+// ipv6独立的syn包，没有载荷
+pub fn build_pkt6_syn(seq: u32) -> Rc<Packet> {
+    build_pkt6_ext(seq, true, false, Default::default(), &[])
+}
+
+// ipv6的包，载荷为一行
+pub fn build_pkt6_line(seq: u32, payload: [u8;10]) -> Rc<Packet> {
+    build_pkt6_ext(seq, false, false, Default::default(), &payload)
+}
+
+// ipv6独立的fin包，没有载荷
+pub fn build_pkt6_fin(seq: u32) -> Rc<Packet> {
+    build_pkt6_ext(seq, false, true, Default::default(), &[])
+}
+
+// 任意长度的载荷
+pub fn build_pkt_payload(seq: u32, payload: &[u8]) -> Rc<Packet> {
+    let builder = PacketBuilder::
+    ethernet2([1,2,3,4,5,6],     //source mac
+              [7,8,9,10,11,12]) //destionation mac
+        .ipv4([192,168,1,1], //source ip
+              [192,168,1,2], //desitionation ip
+              20)            //time to life
+        .tcp(25,    //source port 
+             4000,  //desitnation port
+             seq,     //sequence number
+             1024) //window size
+        .ack(123); //ack flag + the ack number
+
+    let mut result = Vec::<u8>::with_capacity(builder.size(payload.len()));
+    builder.write(&mut result, payload).unwrap();
+    
+    Packet::new(1, result.len(), &result).unwrap()
+}
+
+// 任意地址和标志的tcp包。flags中的字符：S syn，A ack，F fin，R rst，P psh
+pub fn build_tcp(src: ([u8;4], u16), dst: ([u8;4], u16), seq: u32, ack: u32, flags: &str, payload: &[u8]) -> Rc<Packet> {
+    let mut builder = PacketBuilder::
+    ethernet2([1,2,3,4,5,6], [7,8,9,10,11,12])
+        .ipv4(src.0, dst.0, 20)
+        .tcp(src.1, dst.1, seq, 1024);
+    for flag in flags.chars() {
+        builder = match flag {
+            'S' => builder.syn(),
+            'A' => builder.ack(ack),
+            'F' => builder.fin(),
+            'R' => builder.rst(),
+            'P' => builder.psh(),
+            _ => builder,
+        };
+    }
+
+    let mut result = Vec::<u8>::with_capacity(builder.size(payload.len()));
+    builder.write(&mut result, payload).unwrap();
+    Packet::new(1, result.len(), &result).unwrap()
+}
+
+// 以太网上没有选项的ipv4包中，ip和tcp校验和的位置
+pub const IP_CSUM: usize = 24;
+pub const TCP_CSUM: usize = 50;
+
+// 改坏一个字节的包，没有解码
+pub fn corrupt(pkt: &Packet, index: usize) -> Rc<Packet> {
+    let mut data = pkt.to_vec();
+    data[index] ^= 0xff;
+    Packet::new(pkt.timestamp, data.len(), &data).unwrap()
+}
+
+pub const CLIENT: ([u8;4], u16) = ([192,168,1,2], 4000);
+pub const SERVER: ([u8;4], u16) = ([192,168,1,1], 25);
+
+// 解码好的build_tcp
+pub fn tcp(src: ([u8;4], u16), dst: ([u8;4], u16), seq: u32, ack: u32, flags: &str, payload: &[u8]) -> Rc<Packet> {
+    let pkt = build_tcp(src, dst, seq, ack, flags, payload);
+    pkt.decode().unwrap();
+    pkt
+}
+
+pub fn c2s(seq: u32, ack: u32, flags: &str, payload: &[u8]) -> Rc<Packet> {
+    tcp(CLIENT, SERVER, seq, ack, flags, payload)
+}
+
+pub fn s2c(seq: u32, ack: u32, flags: &str, payload: &[u8]) -> Rc<Packet> {
+    tcp(SERVER, CLIENT, seq, ack, flags, payload)
+}
+
+// 同c2s，时间戳为ts
+pub fn c2s_at(ts: u128, seq: u32, ack: u32, flags: &str, payload: &[u8]) -> Rc<Packet> {
+    let frame = build_tcp(CLIENT, SERVER, seq, ack, flags, payload);
+    let pkt = Packet::new(ts, frame.len(), &frame).unwrap();
+    pkt.decode().unwrap();
+    pkt
+}
+
+// 客户端的第一行作为User，服务器的第一行作为Pass
+pub struct LineParser;
+impl Parser for LineParser {
+    fn c2s_parser(&self, stream: *const PktStrm, mut meta_tx: mpsc::Sender<Meta>) -> Pin<Box<dyn Future<Output = ()>>> {
+        Box::pin(async move {
+            let stm: &mut PktStrm;
+            unsafe { stm = &mut *(stream as *mut PktStrm); }
+
+            let line = stm.readline().await.unwrap();
+            let _ = meta_tx.send(Meta::Smtp(MetaSmtp::User(line))).await;
+        })
+    }
+
+    fn s2c_parser(&self, stream: *const PktStrm, mut meta_tx: mpsc::Sender<Meta>) -> Pin<Box<dyn Future<Output = ()>>> {
+        Box::pin(async move {
+            let stm: &mut PktStrm;
+            unsafe { stm = &mut *(stream as *mut PktStrm); }
+
+            let line = stm.readline().await.unwrap();
+            let _ = meta_tx.send(Meta::Smtp(MetaSmtp::Pass(line))).await;
+        })
+    }
+}
+
+// 客户端的每一行作为一个User，跳过缺失，直到流结束
+pub struct LinesParser;
+impl Parser for LinesParser {
+    fn c2s_parser(&self, stream: *const PktStrm, mut meta_tx: mpsc::Sender<Meta>) -> Pin<Box<dyn Future<Output = ()>>> {
+        Box::pin(async move {
+            let stm: &mut PktStrm;
+            unsafe { stm = &mut *(stream as *mut PktStrm); }
+
+            loop {
+                let line = stm.readline().await.unwrap();
+                if line.is_empty() {
+                    if stm.take_gap().is_some() {
+                        continue;
+                    }
+                    break;
+                }
+                let _ = meta_tx.send(Meta::Smtp(MetaSmtp::User(line))).await;
+            }
+        })
+    }
+}
+
+// meta中User的内容
+pub fn users(metas: impl IntoIterator<Item = Meta>) -> Vec<String> {
+    metas.into_iter().filter_map(|meta| match meta {
+        Meta::Smtp(MetaSmtp::User(line)) => Some(line),
+        _ => None,
+    }).collect()
+}
+
+// 取出task中所有的meta，返回其中User的内容
+pub fn lines(task: &mut Task) -> Vec<String> {
+    users(std::iter::from_fn(|| task.get_meta()))
+}
@@ -0,0 +1,64 @@
+mod common;
+
+use memerge::*;
+use crate::common::*;
+
+// 三次握手，四次挥手
+#[test]
+fn test_conn_lifecycle() {
+    let mut task = Task::new();
+    assert_eq!(ConnState::Init, task.conn_state());
+    task.run(c2s(100, 0, "S", &[]), PktDirection::Unknown);
+    assert_eq!(ConnState::SynSent, task.conn_state());
+    task.run(s2c(1000, 101, "SA", &[]), PktDirection::Unknown);
+    assert_eq!(ConnState::SynRcvd, task.conn_state());
+    task.run(c2s(101, 1001, "A", &[]), PktDirection::Unknown);
+    assert_eq!(ConnState::Established, task.conn_state());
+    assert_eq!(Some(100), task.isn(PktDirection::Client2Server));
+    assert_eq!(Some(1000), task.isn(PktDirection::Server2Client));
+    assert!(!task.is_midstream());
+
+    task.run(c2s(101, 1001, "AF", &[]), PktDirection::Unknown);
+    assert_eq!(ConnState::FinWait, task.conn_state());
+    task.run(s2c(1001, 102, "AF", &[]), PktDirection::Unknown);
+    assert_eq!(ConnState::Closed, task.conn_state());
+    assert!(!task.is_rejected());
+}
+
+// 中途接入的连接直接Established，不知道isn
+#[test]
+fn test_conn_midstream() {
+    let mut task = Task::new();
+    task.run(s2c(1001, 101, "AP", &[]), PktDirection::Unknown);
+    assert_eq!(ConnState::Established, task.conn_state());
+    assert!(task.is_midstream());
+    assert_eq!(None, task.isn(PktDirection::Client2Server));
+    assert_eq!(None, task.isn(PktDirection::Server2Client));
+
+    // 只看到syn+ack，客户端的isn从确认号得到
+    let mut task = Task::new();
+    task.run(s2c(1000, 101, "SA", &[]), PktDirection::Unknown);
+    assert_eq!(ConnState::SynRcvd, task.conn_state());
+    assert!(!task.is_midstream());
+    assert_eq!(Some(100), task.isn(PktDirection::Client2Server));
+    assert_eq!(Some(1000), task.isn(PktDirection::Server2Client));
+}
+
+// 服务器用rst拒绝连接，握手中的连接是半开的
+#[test]
+fn test_conn_rejected() {
+    let mut task = Task::new();
+    task.run(c2s(100, 0, "S", &[]), PktDirection::Unknown);
+    task.run(s2c(0, 101, "RA", &[]), PktDirection::Unknown);
+    assert_eq!(ConnState::Reset, task.conn_state());
+    assert!(task.is_rejected());
+
+    // 握手完成之后的rst不算拒绝
+    let mut task = Task::new();
+    task.run(c2s(100, 0, "S", &[]), PktDirection::Unknown);
+    task.run(s2c(1000, 101, "SA", &[]), PktDirection::Unknown);
+    task.run(c2s(101, 1001, "A", &[]), PktDirection::Unknown);
+    task.run(c2s(101, 1001, "R", &[]), PktDirection::Unknown);
+    assert_eq!(ConnState::Reset, task.conn_state());
+    assert!(!task.is_rejected());
+}
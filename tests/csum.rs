@@ -0,0 +1,99 @@
+mod common;
+
+use futures_channel::mpsc;
+use core::{future::Future, pin::Pin};
+use memerge::*;
+use crate::common::*;
+
+fn csum_state(pkt: &Packet) -> CsumState {
+    pkt.header.borrow().as_ref().unwrap().csum
+}
+
+#[test]
+fn test_csum_good() {
+    let pkt = build_pkt(1, false);
+    assert!(pkt.decode().is_ok());
+    assert_eq!(CsumState::Unchecked, csum_state(&pkt));
+    assert!(pkt.check_csum(CsumPolicy::Drop).is_ok());
+    assert_eq!(CsumState::Good, csum_state(&pkt));
+
+    let pkt = build_pkt6(1, false);
+    assert!(pkt.decode_with_csum(CsumPolicy::Drop).is_ok());
+    assert_eq!(CsumState::Good, csum_state(&pkt));
+}
+
+#[test]
+fn test_csum_policy() {
+    let good = build_pkt(1, false);
+    for index in [IP_CSUM, TCP_CSUM, good.len() - 1] {
+        let pkt = corrupt(&good, index);
+        assert!(pkt.decode_with_csum(CsumPolicy::Off).is_ok());
+        assert_eq!(CsumState::Unchecked, csum_state(&pkt));
+
+        assert!(pkt.decode_with_csum(CsumPolicy::Accept).is_ok());
+        assert_eq!(CsumState::Bad, csum_state(&pkt));
+
+        assert!(pkt.decode_with_csum(CsumPolicy::Flag).is_ok());
+        assert_eq!(CsumState::Flagged, csum_state(&pkt));
+
+        let offset = if index == IP_CSUM { 14 } else { 34 };
+        assert_eq!(Err(PacketError::BadCsum { offset }), pkt.decode_with_csum(CsumPolicy::Drop));
+        // 已经校验过的，按新的策略处理
+        assert!(pkt.check_csum(CsumPolicy::Accept).is_ok());
+        assert_eq!(CsumState::Bad, csum_state(&pkt));
+    }
+
+    // 截断的包无法校验
+    let pkt = Packet::new(1, good.len() - 1, &good).unwrap();
+    assert!(pkt.decode_with_csum(CsumPolicy::Drop).is_ok());
+    assert_eq!(CsumState::Unchecked, csum_state(&pkt));
+}
+
+// 校验和错误的包内容不同，不能混进重组的数据里
+#[test] #[cfg(not(miri))]
+fn test_csum_task() {
+    struct CsumTask;
+    impl Parser for CsumTask {
+        fn c2s_parser(&self, stream: *const PktStrm, _meta_tx: mpsc::Sender<Meta>) -> Pin<Box<dyn Future<Output = ()>>> {        
+            Box::pin(async move {
+                let stream_ref: &mut PktStrm;
+                unsafe { stream_ref = &mut *(stream as *mut PktStrm); }
+
+                let res = stream_ref.readline().await.unwrap();
+                assert_eq!("1234\r\n", &res);
+                let res = stream_ref.readline().await.unwrap();
+                assert_eq!("56781234\r\n", &res);
+                let res = stream_ref.readline().await.unwrap();
+                assert_eq!("\r\n", &res);
+                let res = stream_ref.readline().await.unwrap();
+                assert_eq!("\r\n", &res);
+                let res = stream_ref.readline().await.unwrap();
+                assert_eq!("", res);
+            })
+        }
+    }
+
+    for policy in [CsumPolicy::Flag, CsumPolicy::Drop] {
+        let syn = build_pkt_syn(1);
+        let pkt1 = build_pkt_line(2, *b"1234\r\n5678");
+        let pkt2 = build_pkt_line(12, *b"1234\r\n\r\n\r\n");
+        // 攻击者先发一个校验和错误、内容不同的包
+        let evil = corrupt(&build_pkt_line(2, *b"abcd\r\nefgh"), TCP_CSUM);
+        let fin = build_pkt_fin(22);
+        for pkt in [&syn, &pkt1, &pkt2, &evil, &fin] {
+            let _ = pkt.decode();
+        }
+
+        let dir = PktDirection::Client2Server;
+        let mut task = Task::new_with_parser(CsumTask);
+        task.set_csum_policy(policy);
+        assert_eq!(policy, task.csum_policy());
+        task.run(syn, dir.clone());
+        task.run(evil, dir.clone());
+        task.run(pkt1, dir.clone());
+        task.run(pkt2, dir.clone());
+        assert_eq!(TaskState::Start, task.parser_state(dir.clone()));
+        task.run(fin, dir.clone());
+        assert_eq!(TaskState::End, task.parser_state(dir.clone()));
+    }
+}
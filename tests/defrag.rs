@@ -0,0 +1,243 @@
+mod common;
+
+use etherparse::*;
+use memerge::*;
+use std::rc::Rc;
+use crate::common::*;
+
+const ETH_LEN: usize = 14;
+
+// 把一个ipv4的包按frag_size切成分片。frag_size需要是8的倍数
+fn frag_ipv4(pkt: &Packet, frag_size: usize) -> Vec<Vec<u8>> {
+    let data = &pkt[..pkt.data_len()];
+    let (ipv4h, _) = Ipv4Header::from_slice(&data[ETH_LEN..]).unwrap();
+    let payload = &data[ETH_LEN + ipv4h.header_len()..];
+
+    let mut frags = Vec::new();
+    for (i, chunk) in payload.chunks(frag_size).enumerate() {
+        let mut h = ipv4h.clone();
+        h.identification = 0x1234;
+        h.fragments_offset = (i * frag_size / 8) as u16;
+        h.more_fragments = (i + 1) * frag_size < payload.len();
+        h.set_payload_len(chunk.len()).unwrap();
+
+        let mut frag = data[..ETH_LEN].to_vec();
+        h.write(&mut frag).unwrap();
+        frag.extend_from_slice(chunk);
+        frags.push(frag);
+    }
+    frags
+}
+
+// 把一个ipv6的包按frag_size切成分片，插入分片头
+fn frag_ipv6(pkt: &Packet, frag_size: usize) -> Vec<Vec<u8>> {
+    let data = &pkt[..pkt.data_len()];
+    let (ipv6h, _) = Ipv6Header::from_slice(&data[ETH_LEN..]).unwrap();
+    let payload = &data[ETH_LEN + Ipv6Header::SERIALIZED_SIZE..];
+
+    let mut frags = Vec::new();
+    for (i, chunk) in payload.chunks(frag_size).enumerate() {
+        let more = (i + 1) * frag_size < payload.len();
+        let mut h = ipv6h.clone();
+        h.next_header = ip_number::IPV6_FRAG;
+        h.set_payload_length(8 + chunk.len()).unwrap();
+
+        let mut frag = data[..ETH_LEN].to_vec();
+        h.write(&mut frag).unwrap();
+        let offset_flags = ((i * frag_size / 8) as u16) << 3 | more as u16;
+        frag.extend_from_slice(&[ipv6h.next_header, 0]);
+        frag.extend_from_slice(&offset_flags.to_be_bytes());
+        frag.extend_from_slice(&0x5678u32.to_be_bytes());
+        frag.extend_from_slice(chunk);
+        frags.push(frag);
+    }
+    frags
+}
+
+fn to_pkt(ts: u128, data: &[u8]) -> Rc<Packet> {
+    Packet::new(ts, data.len(), data).unwrap()
+}
+
+fn payload_of(pkt: &Packet) -> Vec<u8> {
+    let header = pkt.header.borrow();
+    let header = header.as_ref().unwrap();
+    pkt[header.payload_offset..header.payload_offset + header.payload_len].to_vec()
+}
+
+fn make_payload(len: usize) -> Vec<u8> {
+    (0..len).map(|i| i as u8).collect()
+}
+
+// 不是分片的包，原样返回
+#[test]
+fn test_defrag_pass() {
+    let mut defrag = Defrag::new();
+    let pkt = build_pkt(1, false);
+    let ret = defrag.push(pkt.clone()).unwrap();
+    assert!(Rc::ptr_eq(&pkt, &ret));
+    assert!(defrag.is_empty());
+}
+
+// ipv4分片，顺序到来
+#[test]
+fn test_defrag_ipv4() {
+    let payload = make_payload(100);
+    let pkt = build_pkt_payload(1000, &payload);
+    let frags = frag_ipv4(&pkt, 48);
+    assert_eq!(3, frags.len());
+
+    let mut defrag = Defrag::new();
+    assert!(defrag.push(to_pkt(1, &frags[0])).is_none());
+    assert!(to_pkt(1, &frags[1]).decode().is_err());
+    assert!(defrag.push(to_pkt(1, &frags[1])).is_none());
+    assert_eq!(1, defrag.len());
+    let ret = defrag.push(to_pkt(2, &frags[2])).unwrap();
+    assert!(defrag.is_empty());
+
+    // 除了分片时改过的identification，其余和原始的包一致
+    assert_eq!(pkt.data_len(), ret.data_len());
+    assert_eq!(pkt[..18], ret[..18]);
+    assert_eq!(pkt[20..24], ret[20..24]);
+    assert_eq!(pkt[26..pkt.data_len()], ret[26..ret.data_len()]);
+    assert!(ret.decode().is_ok());
+    assert_eq!(1000, ret.seq());
+    assert_eq!(payload, payload_of(&ret));
+    assert_eq!(2, ret.timestamp);
+}
+
+// ipv4分片，乱序到来
+#[test]
+fn test_defrag_ipv4_disorder() {
+    let payload = make_payload(100);
+    let pkt = build_pkt_payload(1000, &payload);
+    let frags = frag_ipv4(&pkt, 32);
+    assert_eq!(4, frags.len());
+
+    let mut defrag = Defrag::new();
+    assert!(defrag.push(to_pkt(1, &frags[3])).is_none());
+    assert!(defrag.push(to_pkt(1, &frags[1])).is_none());
+    assert!(defrag.push(to_pkt(1, &frags[0])).is_none());
+    let ret = defrag.push(to_pkt(1, &frags[2])).unwrap();
+    assert!(ret.decode().is_ok());
+    assert_eq!(payload, payload_of(&ret));
+}
+
+// ipv6分片
+#[test]
+fn test_defrag_ipv6() {
+    let payload = make_payload(100);
+    let pkt = build_pkt6_ext(1000, false, false, Default::default(), &payload);
+    let frags = frag_ipv6(&pkt, 56);
+    assert_eq!(3, frags.len());
+
+    let mut defrag = Defrag::new();
+    assert!(defrag.push(to_pkt(1, &frags[2])).is_none());
+    assert!(defrag.push(to_pkt(1, &frags[0])).is_none());
+    let ret = defrag.push(to_pkt(1, &frags[1])).unwrap();
+
+    assert_eq!(pkt[..pkt.data_len()], ret[..ret.data_len()]);
+    assert!(ret.decode().is_ok());
+    assert_eq!(1000, ret.seq());
+    assert_eq!(payload, payload_of(&ret));
+}
+
+// 重叠的分片，按策略取数据
+#[test]
+fn test_defrag_overlap() {
+    let payload = make_payload(64);
+    let pkt = build_pkt_payload(1000, &payload);
+    let frags = frag_ipv4(&pkt, 48);
+    assert_eq!(2, frags.len());
+
+    // 第二个分片的重复，内容不同
+    let mut dup = frags[1].clone();
+    let len = dup.len();
+    for byte in &mut dup[len - 8..] {
+        *byte = 0xff;
+    }
+
+    let mut defrag = Defrag::new();
+    defrag.set_policy(FragPolicy::First);
+    assert!(defrag.push(to_pkt(1, &frags[0])).is_none());
+    assert!(defrag.push(to_pkt(1, &frags[1])).is_some());
+    let mut defrag = Defrag::new();
+    defrag.set_policy(FragPolicy::First);
+    assert!(defrag.push(to_pkt(1, &frags[1])).is_none());
+    assert!(defrag.push(to_pkt(1, &dup)).is_none());
+    let ret = defrag.push(to_pkt(1, &frags[0])).unwrap();
+    assert!(ret.decode().is_ok());
+    assert_eq!(payload, payload_of(&ret));
+
+    let mut defrag = Defrag::new();
+    defrag.set_policy(FragPolicy::Last);
+    assert!(defrag.push(to_pkt(1, &frags[1])).is_none());
+    assert!(defrag.push(to_pkt(1, &dup)).is_none());
+    let ret = defrag.push(to_pkt(1, &frags[0])).unwrap();
+    assert!(ret.decode().is_ok());
+    assert_eq!(&[0xff; 8], &payload_of(&ret)[56..]);
+}
+
+// 超时的分片被清除
+#[test]
+fn test_defrag_timeout() {
+    let payload = make_payload(100);
+    let pkt = build_pkt_payload(1000, &payload);
+    let frags = frag_ipv4(&pkt, 48);
+
+    let mut defrag = Defrag::new();
+    defrag.set_timeout(1000);
+    assert!(defrag.push(to_pkt(100, &frags[0])).is_none());
+    assert!(defrag.push(to_pkt(200, &frags[1])).is_none());
+    defrag.timeout(900);
+    assert_eq!(1, defrag.len());
+    defrag.timeout(1200);
+    assert!(defrag.is_empty());
+
+    // 过期的分片不会和新的分片拼在一起
+    assert!(defrag.push(to_pkt(100, &frags[0])).is_none());
+    assert!(defrag.push(to_pkt(200, &frags[1])).is_none());
+    assert!(defrag.push(to_pkt(2000, &frags[2])).is_none());
+    assert_eq!(1, defrag.len());
+}
+
+// 重组之后的包进入PktStrm
+#[test]
+fn test_defrag_strm() {
+    let payload = make_payload(100);
+    let pkt1 = build_pkt_payload(1, &payload);
+    let pkt2 = build_pkt_payload(101, &payload);
+    let _ = pkt2.decode();
+
+    let mut defrag = Defrag::new();
+    let mut stm = PktStrm::new();
+    for frag in frag_ipv4(&pkt1, 40).iter().rev() {
+        if let Some(pkt) = defrag.push(to_pkt(1, frag)) {
+            assert!(pkt.decode().is_ok());
+            stm.push(pkt);
+        }
+    }
+    stm.push(pkt2);
+
+    assert_eq!(1, stm.pop_ord_data().unwrap().seq());
+    assert_eq!(101, stm.pop_ord_data().unwrap().seq());
+    assert!(stm.is_empty());
+}
+
+// raw ip的分片，重组后保留链路层类型
+#[test]
+fn test_defrag_raw() {
+    let payload = make_payload(100);
+    let pkt = build_pkt_payload(1000, &payload);
+
+    let mut defrag = Defrag::new();
+    let mut ret = None;
+    for frag in frag_ipv4(&pkt, 48) {
+        let frag = Packet::new_with_link(1, frag.len() - ETH_LEN, &frag[ETH_LEN..], LinkType::Raw).unwrap();
+        ret = defrag.push(frag);
+    }
+    let ret = ret.unwrap();
+    assert_eq!(LinkType::Raw, ret.link_type());
+    assert!(ret.decode().is_ok());
+    assert_eq!(1000, ret.seq());
+    assert_eq!(payload, payload_of(&ret));
+}
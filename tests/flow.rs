@@ -0,0 +1,92 @@
+mod common;
+
+use etherparse::*;
+use memerge::*;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use crate::common::*;
+
+#[test]
+fn test_flow_key() {
+    let c2s = build_tcp(CLIENT, SERVER, 1, 0, "S", &[]);
+    let s2c = build_tcp(SERVER, CLIENT, 100, 2, "SA", &[]);
+    // 没有解码
+    assert_eq!(None, FlowKey::from_pkt(&c2s));
+    let _ = c2s.decode();
+    let _ = s2c.decode();
+
+    let key = FlowKey::from_pkt(&c2s).unwrap();
+    assert_eq!(IpAddr::from([192,168,1,2]), key.saddr);
+    assert_eq!(IpAddr::from([192,168,1,1]), key.daddr);
+    assert_eq!(4000, key.sport);
+    assert_eq!(25, key.dport);
+    assert_eq!(6, key.proto);
+    assert_eq!(None, key.vlan);
+    assert_eq!(None, key.tunnel_id);
+
+    let rkey = FlowKey::from_pkt(&s2c).unwrap();
+    assert_eq!(key.reverse(), rkey);
+    assert_eq!(key, rkey.reverse());
+    assert_ne!(key, rkey);
+    assert_eq!(key.canonical(), rkey.canonical());
+    assert!(rkey.is_canonical());
+    assert!(!key.is_canonical());
+    assert_eq!(key.flow_hash(), rkey.flow_hash());
+
+    let other = build_tcp(CLIENT, ([192,168,1,1], 80), 1, 0, "S", &[]);
+    let _ = other.decode();
+    let okey = FlowKey::from_pkt(&other).unwrap();
+    assert_ne!(key.canonical(), okey.canonical());
+    assert_ne!(key.flow_hash(), okey.flow_hash());
+
+    let mut map: FlowMap<u32> = FlowMap::default();
+    map.insert(key.canonical(), 1);
+    map.insert(okey.canonical(), 2);
+    assert_eq!(Some(&1), map.get(&rkey.canonical()));
+    assert_eq!(Some(&2), map.get(&okey.canonical()));
+}
+
+// 同样的五元组，vlan不同是不同的连接
+#[test]
+fn test_flow_key_vlan() {
+    let build = |vlan: u16| {
+        let builder = PacketBuilder::
+        ethernet2([1,2,3,4,5,6], [7,8,9,10,11,12])
+            .single_vlan(vlan)
+            .ipv6(IPV6_SRC, IPV6_DST, 20)
+            .udp(53, 5353);
+        let mut result = Vec::<u8>::with_capacity(builder.size(4));
+        builder.write(&mut result, &[1,2,3,4]).unwrap();
+        let pkt = Packet::new(1, result.len(), &result).unwrap();
+        let _ = pkt.decode();
+        FlowKey::from_pkt(&pkt).unwrap()
+    };
+
+    let key1 = build(10);
+    let key2 = build(20);
+    assert_eq!(Some(10), key1.vlan);
+    assert_eq!(17, key1.proto);
+    assert_eq!(IpAddr::from(IPV6_SRC), key1.saddr);
+    assert_ne!(key1, key2);
+    assert_ne!(key1.flow_hash(), key2.flow_hash());
+}
+
+// hash分布不能太差
+#[test]
+fn test_flow_hash() {
+    let mut hashes = HashSet::new();
+    for port in 0..1000 {
+        let key = FlowKey {
+            saddr: IpAddr::from([10,0,0,1]),
+            daddr: IpAddr::from([10,0,0,2]),
+            sport: 1024 + port,
+            dport: 80,
+            proto: 6,
+            vlan: None,
+            tunnel_id: None,
+        };
+        assert_eq!(key.flow_hash(), key.reverse().flow_hash());
+        hashes.insert(key.flow_hash() % 256);
+    }
+    assert!(hashes.len() > 200);
+}
@@ -0,0 +1,226 @@
+mod common;
+
+use memerge::*;
+use memerge::smtp::MetaSmtp;
+use std::net::IpAddr;
+use std::rc::Rc;
+use crate::common::*;
+
+fn line_table() -> FlowTable {
+    FlowTable::new(|_| Some(Box::new(LineParser)))
+}
+
+fn metas(flow_table: &mut FlowTable) -> Vec<(FlowKey, String)> {
+    let mut ret = Vec::new();
+    while let Some((key, meta)) = flow_table.get_meta() {
+        match meta {
+            Meta::Smtp(MetaSmtp::User(line)) => ret.push((key, format!("c2s {}", line))),
+            Meta::Smtp(MetaSmtp::Pass(line)) => ret.push((key, format!("s2c {}", line))),
+            _ => {}
+        }
+    }
+    ret
+}
+
+// 完整的连接，两个方向的数据分到对应的流，fin之后连接被删除
+#[test] #[cfg(not(miri))]
+fn test_flow_table() {
+    let mut flow_table = line_table();
+    let key = flow_table.run(c2s(100, 1001, "S", &[])).unwrap().unwrap();
+    assert_eq!(IpAddr::from(CLIENT.0), key.saddr);
+    assert_eq!(CLIENT.1, key.sport);
+    assert_eq!(1, flow_table.len());
+
+    assert_eq!(Some(key), flow_table.run(s2c(1000, 101, "SA", &[])).unwrap());
+    assert_eq!(Some(key), flow_table.run(c2s(101, 1001, "A", &[])).unwrap());
+    flow_table.run(s2c(1001, 101, "AP", b"220 ok\r\n")).unwrap();
+    flow_table.run(c2s(101, 1001, "AP", b"HELO\r\n")).unwrap();
+    assert_eq!(vec![(key, "s2c 220 ok\r\n".to_string()), (key, "c2s HELO\r\n".to_string())], metas(&mut flow_table));
+    assert_eq!(1, flow_table.len());
+    assert!(flow_table.task(&key.reverse()).is_some());
+
+    flow_table.run(c2s(107, 1001, "AF", &[])).unwrap();
+    assert_eq!(1, flow_table.len());
+    flow_table.run(s2c(1009, 101, "AF", &[])).unwrap();
+    assert!(flow_table.is_empty());
+
+    // 挥手最后的ack不会重新建立连接，新的syn可以
+    assert_eq!(None, flow_table.run(c2s(108, 1001, "A", &[])).unwrap());
+    assert!(flow_table.is_empty());
+    assert_eq!(Some(key), flow_table.run(c2s(5000, 1001, "S", &[])).unwrap());
+    assert_eq!(1, flow_table.len());
+}
+
+// fin先于乱序的数据到达，数据到达，解析器结束之后才删除连接
+#[test] #[cfg(not(miri))]
+fn test_flow_table_close_reorder() {
+    let mut flow_table = line_table();
+    let key = flow_table.run(c2s(100, 1001, "S", &[])).unwrap().unwrap();
+    flow_table.run(s2c(1000, 101, "SA", &[])).unwrap();
+    flow_table.run(s2c(1001, 101, "AP", b"220 ok\r\n")).unwrap();
+    flow_table.run(c2s(107, 1001, "AF", &[])).unwrap();
+    flow_table.run(s2c(1009, 101, "AF", &[])).unwrap();
+    assert_eq!(Some(ConnState::Closed), flow_table.task(&key).map(|task| task.conn_state()));
+    assert_eq!(vec![(key, "s2c 220 ok\r\n".to_string())], metas(&mut flow_table));
+
+    flow_table.run(c2s(101, 1001, "AP", b"HELO\r\n")).unwrap();
+    assert_eq!(vec![(key, "c2s HELO\r\n".to_string())], metas(&mut flow_table));
+    assert!(flow_table.is_empty());
+}
+
+// 校验和错误被标记的syn和fin不改变连接状态，伪造的fin不能删除连接
+#[test] #[cfg(not(miri))]
+fn test_flow_table_flagged_fin() {
+    let forged = |pkt: Rc<Packet>| corrupt(&pkt, TCP_CSUM);
+    let mut flow_table = line_table();
+    flow_table.set_csum_policy(CsumPolicy::Flag);
+    let key = flow_table.run(c2s(100, 1001, "S", &[])).unwrap().unwrap();
+    flow_table.run(s2c(1000, 101, "SA", &[])).unwrap();
+    flow_table.run(s2c(1001, 101, "AP", b"220 ok\r\n")).unwrap();
+    flow_table.run(c2s(101, 1001, "AP", b"HELO\r\n")).unwrap();
+    assert_eq!(2, metas(&mut flow_table).len());
+
+    flow_table.run(forged(c2s(5000, 0, "S", &[]))).unwrap();
+    flow_table.run(forged(c2s(107, 1009, "AF", &[]))).unwrap();
+    flow_table.run(forged(s2c(1009, 108, "AF", &[]))).unwrap();
+    let task = flow_table.task(&key).unwrap();
+    assert_eq!(ConnState::Established, task.conn_state());
+    assert_eq!(Some(100), task.isn(PktDirection::Client2Server));
+
+    flow_table.run(c2s(107, 1009, "AF", &[])).unwrap();
+    flow_table.run(s2c(1009, 108, "AF", &[])).unwrap();
+    assert!(flow_table.is_empty());
+}
+
+// 解析器阻塞在缺失上时连接不删除，流超时之后收集最后的meta再删除。之后的包不再建立连接，直到CLOSED_LINGER过去
+#[test] #[cfg(not(miri))]
+fn test_flow_table_close_gap() {
+    let mut flow_table = line_table();
+    flow_table.set_strm_timeout(1000);
+    let key = flow_table.run(c2s(100, 1001, "S", &[])).unwrap().unwrap();
+    flow_table.run(s2c(1000, 101, "SA", &[])).unwrap();
+    flow_table.run(s2c(1001, 101, "AP", b"220 ok\r\n")).unwrap();
+    flow_table.run(c2s(104, 1001, "AP", b"LO\r\n")).unwrap();
+    flow_table.run(c2s(108, 1001, "AF", &[])).unwrap();
+    flow_table.run(s2c(1009, 101, "AF", &[])).unwrap();
+    assert_eq!(1, flow_table.len());
+    assert_eq!(vec![(key, "s2c 220 ok\r\n".to_string())], metas(&mut flow_table));
+
+    flow_table.timeout(500);
+    assert_eq!(1, flow_table.len());
+    flow_table.timeout(1002);
+    assert_eq!(vec![(key, "c2s ".to_string())], metas(&mut flow_table));
+    assert!(flow_table.is_empty());
+
+    assert_eq!(None, flow_table.run(c2s(108, 1001, "A", &[])).unwrap());
+    flow_table.timeout(20_000);
+    assert_eq!(Some(key), flow_table.run(c2s(108, 1001, "A", &[])).unwrap());
+}
+
+// 没有看到syn，syn+ack决定方向
+#[test] #[cfg(not(miri))]
+fn test_flow_table_synack() {
+    let mut flow_table = line_table();
+    let key = flow_table.run(s2c(1000, 101, "SA", &[])).unwrap().unwrap();
+    assert_eq!(IpAddr::from(CLIENT.0), key.saddr);
+    flow_table.run(c2s(101, 1001, "AP", b"HELO\r\n")).unwrap();
+    assert_eq!(vec![(key, "c2s HELO\r\n".to_string())], metas(&mut flow_table));
+}
+
+// 不同的连接互不影响，窗口内的rst删除连接
+#[test] #[cfg(not(miri))]
+fn test_flow_table_multi() {
+    let mut flow_table = line_table();
+    let other = ([192,168,1,3], 5000);
+    let key1 = flow_table.run(c2s(100, 1001, "S", &[])).unwrap().unwrap();
+    let key2 = flow_table.run(build_tcp(other, SERVER, 500, 0, "S", &[])).unwrap().unwrap();
+    assert_ne!(key1, key2);
+    assert_eq!(2, flow_table.len());
+
+    flow_table.run(build_tcp(other, SERVER, 501, 0, "A", b"two\r\n")).unwrap();
+    flow_table.run(c2s(101, 1001, "A", b"one\r\n")).unwrap();
+    assert_eq!(vec![(key2, "c2s two\r\n".to_string()), (key1, "c2s one\r\n".to_string())], metas(&mut flow_table));
+
+    // 客户端已经确认到1001，之前的rst无效
+    flow_table.run(s2c(1000, 101, "R", &[])).unwrap();
+    assert_eq!(2, flow_table.len());
+    flow_table.run(s2c(1001, 101, "R", &[])).unwrap();
+    assert_eq!(1, flow_table.len());
+    assert!(flow_table.task(&key1).is_none());
+    assert!(flow_table.task(&key2).is_some());
+}
+
+// 工厂不关心的连接，连接数上限，空闲超时
+#[test] #[cfg(not(miri))]
+fn test_flow_table_evict() {
+    let mut flow_table = FlowTable::new(|key| {
+        if key.dport == 25 || key.sport == 25 { Some(Box::new(LineParser)) } else { None }
+    });
+    assert_eq!(None, flow_table.run(build_tcp(CLIENT, ([192,168,1,1], 80), 1, 0, "S", &[])).unwrap());
+    assert!(flow_table.is_empty());
+
+    // 不是tcp，或者解码失败
+    let udp = build_pkt6_udp();
+    assert_eq!(None, flow_table.run(udp).unwrap());
+    let bad = Packet::new(1, 10, &[0; 10]).unwrap();
+    assert!(flow_table.run(bad).is_err());
+
+    flow_table.set_max_flows(1);
+    assert!(flow_table.run(c2s(100, 1001, "S", &[])).unwrap().is_some());
+    assert_eq!(0, flow_table.full_drops());
+    assert_eq!(None, flow_table.run(build_tcp(([192,168,1,3], 5000), SERVER, 1, 0, "S", &[])).unwrap());
+    assert_eq!(1, flow_table.len());
+    assert_eq!(1, flow_table.full_drops());
+    flow_table.run(c2s(101, 1001, "A", &[])).unwrap();
+    assert_eq!(1, flow_table.full_drops());
+
+    flow_table.set_timeout(1000);
+    flow_table.timeout(500);
+    assert_eq!(1, flow_table.len());
+    flow_table.timeout(1002);
+    assert!(flow_table.is_empty());
+}
+
+// 分片先重组再进入连接
+#[test] #[cfg(not(miri))]
+fn test_flow_table_defrag() {
+    let mut flow_table = line_table();
+    let pkt = c2s(101, 1001, "A", b"0123456789012345678901234567890123456789\r\n");
+    let data = pkt.to_vec();
+    // ip头20字节，tcp头20字节，切成两片
+    let (first, second) = ipv4_frags(&data, 48);
+    assert_eq!(None, flow_table.run(Packet::new(1, first.len(), &first).unwrap()).unwrap());
+    let key = flow_table.run(Packet::new(1, second.len(), &second).unwrap()).unwrap().unwrap();
+    assert_eq!(vec![(key, "c2s 0123456789012345678901234567890123456789\r\n".to_string())], metas(&mut flow_table));
+}
+
+fn build_pkt6_udp() -> Rc<Packet> {
+    let builder = etherparse::PacketBuilder::
+    ethernet2([1,2,3,4,5,6], [7,8,9,10,11,12])
+        .ipv6(IPV6_SRC, IPV6_DST, 20)
+        .udp(53, 5353);
+    let mut result = Vec::<u8>::with_capacity(builder.size(4));
+    builder.write(&mut result, &[1,2,3,4]).unwrap();
+    Packet::new(1, result.len(), &result).unwrap()
+}
+
+// 把以太网上的ipv4包在ip载荷的size处切成两片
+fn ipv4_frags(data: &[u8], size: usize) -> (Vec<u8>, Vec<u8>) {
+    let (ipv4h, _) = etherparse::Ipv4Header::from_slice(&data[14..]).unwrap();
+    let payload = &data[34..];
+
+    let mut h = ipv4h.clone();
+    h.more_fragments = true;
+    h.set_payload_len(size).unwrap();
+    let mut first = data[..14].to_vec();
+    h.write(&mut first).unwrap();
+    first.extend_from_slice(&payload[..size]);
+
+    let mut h = ipv4h.clone();
+    h.fragments_offset = (size / 8) as u16;
+    h.set_payload_len(payload.len() - size).unwrap();
+    let mut second = data[..14].to_vec();
+    h.write(&mut second).unwrap();
+    second.extend_from_slice(&payload[size..]);
+    (first, second)
+}
@@ -0,0 +1,158 @@
+mod common;
+
+use futures_channel::mpsc;
+use core::{future::Future, pin::Pin};
+use etherparse::*;
+use memerge::*;
+use std::net::{IpAddr, Ipv6Addr};
+use crate::common::*;
+
+// ipv6 tcp包的解码和地址
+#[test]
+fn test_ipv6_decode() {
+    let pkt = build_pkt6(1, false);
+    assert!(pkt.decode().is_ok());
+
+    let header = pkt.header.borrow();
+    let header = header.as_ref().unwrap();
+    assert!(header.is_ipv6());
+    assert_eq!(Some(IpAddr::V6(Ipv6Addr::from(IPV6_SRC))), header.saddr());
+    assert_eq!(Some(IpAddr::V6(Ipv6Addr::from(IPV6_DST))), header.daddr());
+    assert_eq!(25, header.sport());
+    assert_eq!(4000, header.dport());
+    // 14 以太网 + 40 ipv6 + 20 tcp
+    assert_eq!(74, header.payload_offset);
+    assert_eq!(10, header.payload_len);
+}
+
+// ipv6扩展头链: hop-by-hop -> destination options -> tcp
+#[test]
+fn test_ipv6_ext_chain() {
+    let pad = [1, 4, 0, 0, 0, 0];
+    let ext = Ipv6Extensions {
+        hop_by_hop_options: Some(Ipv6RawExtensionHeader::new_raw(0, &pad).unwrap()),
+        destination_options: Some(Ipv6RawExtensionHeader::new_raw(0, &pad).unwrap()),
+        ..Default::default()
+    };
+    let pkt = build_pkt6_ext(1, false, false, ext, &[1,2,3,4,5,6,7,8,9,10]);
+    assert!(pkt.decode().is_ok());
+
+    let header = pkt.header.borrow();
+    let header = header.as_ref().unwrap();
+    // 14 以太网 + 40 ipv6 + 8 hop-by-hop + 8 destination options + 20 tcp
+    assert_eq!(90, header.payload_offset);
+    assert_eq!(10, header.payload_len);
+    assert_eq!(25, header.sport());
+    assert_eq!(1, pkt.seq());
+}
+
+// 以太网最小帧的填充不能算作载荷
+#[test]
+fn test_eth_padding() {
+    let pkt = build_pkt_nodata(1, false);
+    let mut data = pkt[..pkt.data_len()].to_vec();
+    data.resize(pkt.data_len() + 6, 0);
+    let pkt = Packet::new(1, data.len(), &data).unwrap();
+    assert!(pkt.decode().is_ok());
+    assert_eq!(0, pkt.payload_len());
+}
+
+// 未解码的包也可以打印
+#[test]
+fn test_debug_undecoded() {
+    let pkt = build_pkt6(1, false);
+    let out = format!("{:?}", pkt);
+    assert!(out.starts_with("Packet"));
+    let _ = pkt.decode();
+    let out = format!("{:?}", pkt);
+    assert!(out.starts_with("Some(2001:db8::1):25 -> Some(2001:db8::2):4000"));
+}
+
+// ipv6的包乱序到来，经过task重组成行
+#[test] #[cfg(not(miri))]
+fn test_ipv6_task_readline() {
+    struct StreamTaskReadLine6;
+    impl Parser for StreamTaskReadLine6 {
+        fn c2s_parser(&self, stream: *const PktStrm, _meta_tx: mpsc::Sender<Meta>) -> Pin<Box<dyn Future<Output = ()>>> {        
+            Box::pin(async move {
+                let stream_ref: &mut PktStrm;
+                unsafe { stream_ref = &mut *(stream as *mut PktStrm); }
+
+                let res = stream_ref.readline().await.unwrap();
+                assert_eq!("1234\r\n", &res);
+                let res = stream_ref.readline().await.unwrap();
+                assert_eq!("56781234\r\n", &res);
+                let res = stream_ref.readline().await.unwrap();
+                assert_eq!("56\r\n", &res);
+                let res = stream_ref.readline().await.unwrap();
+                assert_eq!("", res);
+            })
+        }
+    }
+
+    // syn 包seq占一个
+    let syn_pkt_seq = 1;
+    let syn_pkt = build_pkt6_syn(syn_pkt_seq);
+    let _ = syn_pkt.decode();
+    // 2 - 11
+    let seq1 = syn_pkt_seq + 1;
+    let pkt1 = build_pkt6_line(seq1, *b"1234\r\n5678");
+    let _ = pkt1.decode();
+    // 12 - 21
+    let seq2 = seq1 + pkt1.payload_len();
+    let pkt2 = build_pkt6_line(seq2, *b"1234\r\n56\r\n");
+    let _ = pkt2.decode();
+    // 22 无数据，fin
+    let seq3 = seq2 + pkt2.payload_len();
+    let pkt3 = build_pkt6_fin(seq3);
+    let _ = pkt3.decode();
+
+    let dir = PktDirection::Client2Server;
+    let mut task = Task::new_with_parser(StreamTaskReadLine6);
+    assert_eq!(TaskState::Start, task.parser_state(dir.clone()));
+    task.run(syn_pkt, dir.clone());
+    task.run(pkt3, dir.clone());
+    task.run(pkt2, dir.clone());
+    assert_eq!(TaskState::Start, task.parser_state(dir.clone()));
+    task.run(pkt1, dir.clone());
+    assert_eq!(TaskState::End, task.parser_state(dir.clone()));
+}
+
+// ipv6的包，带扩展头，有序包解码器
+#[test] #[cfg(not(miri))]
+fn test_ipv6_task_ordpkt() {
+    struct OrdPktTask6;
+    impl Parser for OrdPktTask6 {
+        fn c2s_parser(&self, stream: *const PktStrm, _meta_tx: mpsc::Sender<Meta>) -> Pin<Box<dyn Future<Output = ()>>> {        
+            Box::pin(async move {
+                let stream_ref: &mut PktStrm;
+                unsafe { stream_ref = &mut *(stream as *mut PktStrm); }
+
+                let pkt = stream_ref.next_ord_pkt().await.unwrap();
+                assert_eq!(1, pkt.seq());
+                let pkt = stream_ref.next_ord_pkt().await.unwrap();
+                assert_eq!(11, pkt.seq());
+                assert_eq!(None, stream_ref.next_ord_pkt().await);
+            })
+        }
+    }
+
+    let pad = [1, 4, 0, 0, 0, 0];
+    let ext = || Ipv6Extensions {
+        hop_by_hop_options: Some(Ipv6RawExtensionHeader::new_raw(0, &pad).unwrap()),
+        ..Default::default()
+    };
+    // 1 - 10
+    let pkt1 = build_pkt6_ext(1, false, false, ext(), &[1,2,3,4,5,6,7,8,9,10]);
+    let _ = pkt1.decode();
+    // 11 - 20, fin
+    let pkt2 = build_pkt6_ext(11, false, true, ext(), &[1,2,3,4,5,6,7,8,9,10]);
+    let _ = pkt2.decode();
+
+    let dir = PktDirection::Client2Server;
+    let mut task = Task::new_with_parser(OrdPktTask6);
+    task.run(pkt1, dir.clone());
+    assert_eq!(TaskState::Start, task.parser_state(dir.clone()));
+    task.run(pkt2, dir.clone());
+    assert_eq!(TaskState::End, task.parser_state(dir.clone()));
+}
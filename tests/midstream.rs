@@ -0,0 +1,112 @@
+mod common;
+
+use futures::executor::block_on;
+use futures_channel::mpsc;
+use futures_util::SinkExt;
+use core::{future::Future, pin::Pin};
+use memerge::*;
+use memerge::smtp::MetaSmtp;
+use crate::common::*;
+
+// 中途接入时先重新同步到行首，之后每一行作为一个User
+struct ResyncParser;
+impl Parser for ResyncParser {
+    fn c2s_parser(&self, stream: *const PktStrm, mut meta_tx: mpsc::Sender<Meta>) -> Pin<Box<dyn Future<Output = ()>>> {
+        Box::pin(async move {
+            let stm: &mut PktStrm;
+            unsafe { stm = &mut *(stream as *mut PktStrm); }
+
+            let _ = stm.peek(1).await;
+            if stm.is_midstream() {
+                stm.resync_line().await;
+            }
+            loop {
+                let line = stm.readline().await.unwrap();
+                if line.is_empty() {
+                    break;
+                }
+                let _ = meta_tx.send(Meta::Smtp(MetaSmtp::User(line))).await;
+            }
+        })
+    }
+}
+
+// isn为0的流，0不是未初始化
+#[test]
+fn test_isn_zero() {
+    let mut stm = PktStrm::new();
+    stm.push(c2s(1, 1001, "A", b"QUIT\r\n"));
+    stm.push(c2s(0, 1001, "S", &[]));
+    assert!(!stm.is_midstream());
+    assert_eq!(Some(0), stm.isn());
+    assert_eq!("QUIT\r\n", block_on(stm.readline()).unwrap());
+
+    let mut stm = PktStrm::new();
+    stm.push(c2s(0, 1001, "A", b"HELO\r\n"));
+    assert!(stm.is_midstream());
+    assert_eq!("HELO\r\n", block_on(stm.readline()).unwrap());
+}
+
+// 不等待时从第一个包开始，之后到的更早的包当作旧的重传
+#[test]
+fn test_midstream_anchor() {
+    let mut stm = PktStrm::new();
+    stm.push(c2s(11, 1001, "A", b"ij\r\nklmnop"));
+    assert!(stm.is_midstream());
+    assert_eq!(11, stm.peek_chunk().unwrap().seq());
+
+    stm.push(c2s(1, 1001, "A", b"abc\r\ndefgh"));
+    stm.expire();
+    block_on(async {
+        assert_eq!("ij\r\n", stm.readline().await.unwrap());
+        assert_eq!(b"klmnop".to_vec(), stm.readn(100).await);
+    });
+}
+
+// 等待期间到的更早的包，从其中最小的seq开始。后面的包或者timeout结束等待
+#[test]
+fn test_midstream_wait() {
+    let mut stm = PktStrm::new();
+    stm.set_midstream_wait(100);
+    stm.push(c2s(21, 1001, "A", b"qrstuvwxyz"));
+    assert!(stm.peek_chunk().is_none());
+    stm.push(c2s_at(50, 11, 1001, "A", b"ij\r\nklmnop"));
+    assert!(stm.peek_chunk().is_none());
+    stm.push(c2s_at(100, 1, 1001, "A", b"abc\r\ndefgh"));
+    assert!(stm.peek_chunk().is_none());
+    stm.push(c2s_at(101, 31, 1001, "A", b"0123456789"));
+    assert_eq!(1, stm.peek_chunk().unwrap().seq());
+
+    let mut stm = PktStrm::new();
+    stm.set_midstream_wait(100);
+    stm.push(c2s(11, 1001, "A", b"ij\r\nklmnop"));
+    stm.push(c2s_at(20, 1, 1001, "A", b"abc\r\ndefgh"));
+    assert!(!stm.timeout(100));
+    assert!(stm.peek_chunk().is_none());
+    assert!(stm.timeout(101));
+    assert_eq!(1, stm.peek_chunk().unwrap().seq());
+
+    // 有syn的流不等待
+    let mut stm = PktStrm::new();
+    stm.set_midstream_wait(100);
+    stm.push(c2s(0, 1001, "S", &[]));
+    stm.push(c2s(1, 1001, "A", b"abc\r\ndefgh"));
+    assert_eq!(1, stm.peek_chunk().unwrap().seq());
+}
+
+// 中途接入的解析器丢掉第一行不完整的部分。task超时结束等待
+#[test] #[cfg(not(miri))]
+fn test_midstream_resync() {
+    let mut task = Task::new_with_parser(ResyncParser);
+    task.set_midstream_wait(100);
+    task.run(c2s(11, 1001, "AP", b":<a>\r\nDATA"), PktDirection::Client2Server);
+    task.run(c2s(21, 1001, "AP", b"\r\nQUIT\r\n"), PktDirection::Client2Server);
+    assert!(lines(&mut task).is_empty());
+    assert!(task.timeout(101));
+    assert_eq!(vec!["DATA\r\n", "QUIT\r\n"], lines(&mut task));
+
+    let mut task = Task::new_with_parser(ResyncParser);
+    task.run(c2s(10, 1001, "S", &[]), PktDirection::Client2Server);
+    task.run(c2s(11, 1001, "AP", b"RCPT\r\n"), PktDirection::Client2Server);
+    assert_eq!(vec!["RCPT\r\n"], lines(&mut task));
+}
@@ -0,0 +1,93 @@
+mod common;
+
+use futures::executor::block_on;
+use memerge::*;
+use crate::common::*;
+
+// 先放入old再放入new，按策略读出重组的数据
+fn reassemble(policy: OverlapPolicy, old: (u32, &[u8]), new: (u32, &[u8])) -> (String, Vec<Anomaly>) {
+    let mut stm = PktStrm::new();
+    stm.set_overlap_policy(policy);
+    stm.push(c2s(old.0, 1, "A", old.1));
+    stm.push(c2s(new.0, 1, "A", new.1));
+    stm.expire();
+
+    let data = block_on(stm.readn(100));
+    let anomalies = std::iter::from_fn(|| stm.take_anomaly()).collect();
+    (String::from_utf8(data).unwrap(), anomalies)
+}
+
+fn check(old: (u32, &[u8]), new: (u32, &[u8]), expect: [&str; 5]) {
+    let policies = [OverlapPolicy::First, OverlapPolicy::Last, OverlapPolicy::Bsd, OverlapPolicy::Linux, OverlapPolicy::Windows];
+    for (policy, expect) in policies.into_iter().zip(expect) {
+        assert_eq!(expect, reassemble(policy, old, new).0, "{:?}", policy);
+    }
+}
+
+// 后到的包起始seq更大
+#[test]
+fn test_overlap_after() {
+    check((1, b"AAAAAAAAAA"), (5, b"BBBBBBBBBB"),
+          ["AAAAAAAAAABBBB", "AAAABBBBBBBBBB", "AAAAAAAAAABBBB", "AAAAAAAAAABBBB", "AAAAAAAAAABBBB"]);
+}
+
+// 后到的包起始seq更小
+#[test]
+fn test_overlap_before() {
+    check((5, b"AAAAAAAAAA"), (1, b"BBBBBBBBBB"),
+          ["BBBBAAAAAAAAAA", "BBBBBBBBBBAAAA", "BBBBBBBBBBAAAA", "BBBBBBBBBBAAAA", "BBBBAAAAAAAAAA"]);
+}
+
+// 起始seq相同，后到的更长
+#[test]
+fn test_overlap_same_start() {
+    check((1, b"AAAAA"), (1, b"BBBBBBBBBB"),
+          ["AAAAABBBBB", "BBBBBBBBBB", "AAAAABBBBB", "BBBBBBBBBB", "AAAAABBBBB"]);
+}
+
+// 后到的包完全覆盖先到的
+#[test]
+fn test_overlap_cover() {
+    check((5, b"AAA"), (1, b"BBBBBBBBBB"),
+          ["BBBBAAABBB", "BBBBBBBBBB", "BBBBBBBBBB", "BBBBBBBBBB", "BBBBBBBBBB"]);
+}
+
+// 数据不一致才报告异常，一致的重传不报告
+#[test]
+fn test_overlap_anomaly() {
+    let (_, anomalies) = reassemble(OverlapPolicy::First, (1, b"AAAAAAAAAA"), (5, b"BBBBBBBBBB"));
+    assert_eq!(vec![Anomaly::Overlap { seq: 5, len: 6 }], anomalies);
+
+    let (data, anomalies) = reassemble(OverlapPolicy::Last, (1, b"AAAAAAAAAA"), (5, b"AAAAAABBBB"));
+    assert_eq!("AAAAAAAAAABBBB", data);
+    assert!(anomalies.is_empty());
+}
+
+// 带fin的包数据全部被丢弃，fin保留
+#[test]
+fn test_overlap_fin() {
+    let mut stm = PktStrm::new();
+    stm.push(c2s(1, 1, "A", b"AAAAAAAAAA"));
+    stm.push(c2s(5, 1, "AF", b"BBBBBB"));
+    block_on(async {
+        assert_eq!(b"AAAAAAAAAA".to_vec(), stm.readn(100).await);
+    });
+    assert!(stm.take_anomaly().is_some());
+}
+
+// task按方向报告异常
+#[test] #[cfg(not(miri))]
+fn test_task_anomaly() {
+    let mut task = Task::new();
+    task.set_overlap_policy(OverlapPolicy::Last);
+    let pkt = build_tcp(SERVER, CLIENT, 1, 1, "A", b"AAAA");
+    pkt.decode().unwrap();
+    task.run(pkt, PktDirection::Server2Client);
+    task.run(c2s(1, 1, "A", b"AAAA"), PktDirection::Client2Server);
+    task.run(c2s(1, 1, "A", b"AAAA"), PktDirection::Client2Server);
+    assert_eq!(None, task.get_anomaly());
+
+    task.run(c2s(3, 1, "A", b"BBBB"), PktDirection::Client2Server);
+    assert_eq!(Some((PktDirection::Client2Server, Anomaly::Overlap { seq: 3, len: 2 })), task.get_anomaly());
+    assert_eq!(None, task.get_anomaly());
+}
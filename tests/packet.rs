@@ -0,0 +1,363 @@
+mod common;
+
+use futures_channel::mpsc;
+use core::{future::Future, pin::Pin};
+use etherparse::*;
+use memerge::*;
+use std::rc::Rc;
+use crate::common::*;
+
+// 巨帧
+#[test]
+fn test_jumbo_pkt() {
+    let payload = vec![b'a'; 9000];
+    let pkt = build_pkt_payload(1, &payload);
+    assert_eq!(9054, pkt.data_len());
+    assert_eq!(9054, pkt.len());
+    assert!(pkt.decode().is_ok());
+    assert_eq!(9000, pkt.payload_len());
+}
+
+// GRO合并之后接近64K的包，经过PktStrm重组
+#[test]
+fn test_gro_pkt() {
+    let payload = vec![b'b'; 65000];
+    let pkt1 = build_pkt_payload(1, &payload);
+    let _ = pkt1.decode();
+    let pkt2 = build_pkt_payload(65001, &payload);
+    let _ = pkt2.decode();
+    assert_eq!(65000, pkt1.payload_len());
+
+    let mut stm = PktStrm::new();
+    stm.push(pkt2);
+    stm.push(pkt1);
+    assert_eq!(1, stm.pop_ord_data().unwrap().seq());
+    assert_eq!(65001, stm.pop_ord_data().unwrap().seq());
+}
+
+// 长度不对返回错误，而不是panic
+#[test]
+fn test_pkt_len_err() {
+    let data = [0u8; 100];
+    assert_eq!(Some(PacketError::Truncated { offset: 100 }), Packet::new(1, 101, &data).err());
+    assert_eq!(Some(PacketError::Oversize { offset: MAX_PACKET_LEN }), Packet::new(1, MAX_PACKET_LEN + 1, &data).err());
+
+    let pkt = Packet::new(1, 60, &data).unwrap();
+    assert_eq!(60, pkt.data_len());
+    assert_eq!(60, pkt.len());
+}
+
+// 借用模式的包被缓存时，缓存的是只有载荷的拷贝。原来的包不变，Task不再持有它
+#[test] #[cfg(not(miri))]
+fn test_borrowed_detach() {
+    struct Pending;
+    impl Parser for Pending {
+        fn c2s_parser(&self, stream: *const PktStrm, _meta_tx: mpsc::Sender<Meta>) -> Pin<Box<dyn Future<Output = ()>>> {
+            Box::pin(async move {
+                let stream_ref: &mut PktStrm;
+                unsafe { stream_ref = &mut *(stream as *mut PktStrm); }
+                let _ = stream_ref.readline().await;
+            })
+        }
+    }
+
+    let dir = PktDirection::Client2Server;
+    let mut task = Task::new_with_parser(Pending);
+    let syn = build_pkt_syn(0);
+    let _ = syn.decode();
+    task.run(syn, dir.clone());
+
+    // seq 11之前缺了数据，包被缓存
+    let pkt = build_pkt(11, false);
+    let mut buf = pkt.data().to_vec();
+    let borrowed = unsafe { Packet::new_borrowed(1, buf.len(), &buf, LinkType::Ethernet).unwrap() };
+    assert!(borrowed.is_borrowed());
+    assert!(borrowed.decode().is_ok());
+    assert_eq!(buf.as_ptr(), borrowed.data().as_ptr());
+
+    let other = Rc::clone(&borrowed);
+    task.run(borrowed, dir.clone());
+    assert_eq!(1, task.steeam_len(dir.clone()));
+    assert_eq!(1, Rc::strong_count(&other));
+    assert!(other.is_borrowed());
+    assert_eq!(buf.as_ptr(), other.data().as_ptr());
+    assert_eq!(pkt.data_len(), other.data_len());
+    assert_eq!(62, other.header.borrow().as_ref().unwrap().payload_offset);
+    drop(other);
+    buf.fill(0);
+}
+
+// 借用模式，ring buffer的同一块内存反复用来装新包。被缓存的包只拷贝载荷
+#[test] #[cfg(not(miri))]
+fn test_borrowed_task() {
+    struct BorrowedTask;
+    impl Parser for BorrowedTask {
+        fn c2s_parser(&self, stream: *const PktStrm, _meta_tx: mpsc::Sender<Meta>) -> Pin<Box<dyn Future<Output = ()>>> {        
+            Box::pin(async move {
+                let stream_ref: &mut PktStrm;
+                unsafe { stream_ref = &mut *(stream as *mut PktStrm); }
+
+                let res = stream_ref.readline().await.unwrap();
+                assert_eq!("1234\r\n", &res);
+                let res = stream_ref.readline().await.unwrap();
+                assert_eq!("56781234\r\n", &res);
+                let res = stream_ref.readline().await.unwrap();
+                assert_eq!("56781234\r\n", &res);
+                let res = stream_ref.readline().await.unwrap();
+                assert_eq!("\r\n", &res);
+                let res = stream_ref.readline().await.unwrap();
+                assert_eq!("\r\n", &res);
+                let res = stream_ref.readline().await.unwrap();
+                assert_eq!("", res);
+            })
+        }
+    }
+
+    let syn = build_pkt_syn(1);
+    let pkt1 = build_pkt_line(2, *b"1234\r\n5678");
+    let pkt2 = build_pkt_line(12, *b"1234\r\n5678");
+    let pkt3 = build_pkt_line(22, *b"1234\r\n\r\n\r\n");
+    let fin = build_pkt_fin(32);
+
+    let dir = PktDirection::Client2Server;
+    let mut task = Task::new_with_parser(BorrowedTask);
+    let mut ring = vec![0u8; 2048];
+    let mut run = |task: &mut Task, pkt: &Rc<Packet>| {
+        ring.fill(0xee);
+        ring[..pkt.data_len()].copy_from_slice(pkt.data());
+        let borrowed = unsafe { Packet::new_borrowed(1, pkt.data_len(), &ring, LinkType::Ethernet).unwrap() };
+        let _ = borrowed.decode();
+        let weak = Rc::downgrade(&borrowed);
+        task.run(borrowed, dir.clone());
+        weak.upgrade()
+    };
+
+    // Task::run返回之后不再持有借用的包，缓存的都是拷贝
+    assert!(run(&mut task, &syn).is_none());
+    assert!(run(&mut task, &pkt1).is_none());
+    assert!(run(&mut task, &pkt3).is_none());
+    assert_eq!(1, task.steeam_len(dir.clone()));
+    run(&mut task, &pkt2);
+    assert_eq!(TaskState::Start, task.parser_state(dir.clone()));
+    run(&mut task, &fin);
+    assert_eq!(TaskState::End, task.parser_state(dir.clone()));
+}
+
+// 解析器跨await持有chunk。chunk引用的是缓存中的拷贝，调用者的内存被复用之后还能读
+#[test] #[cfg(not(miri))]
+fn test_borrowed_chunk() {
+    struct ChunkTask;
+    impl Parser for ChunkTask {
+        fn c2s_parser(&self, stream: *const PktStrm, _meta_tx: mpsc::Sender<Meta>) -> Pin<Box<dyn Future<Output = ()>>> {
+            Box::pin(async move {
+                let stream_ref: &mut PktStrm;
+                unsafe { stream_ref = &mut *(stream as *mut PktStrm); }
+
+                let chunk = stream_ref.next_chunk(100).await.unwrap();
+                let peek = stream_ref.peek_chunk();
+                assert!(peek.is_none());
+                let res = stream_ref.readline().await.unwrap();
+                assert_eq!("1234\r\n", &res);
+                assert_eq!(b"1234\r\n5678", &chunk[..]);
+                assert_eq!(2, chunk.seq());
+                let res = stream_ref.readline().await.unwrap();
+                assert_eq!("5678", res);
+            })
+        }
+    }
+
+    let dir = PktDirection::Client2Server;
+    let mut task = Task::new_with_parser(ChunkTask);
+    let mut ring = vec![0u8; 2048];
+    let mut run = |task: &mut Task, pkt: &Rc<Packet>| {
+        ring.fill(0xee);
+        ring[..pkt.data_len()].copy_from_slice(pkt.data());
+        let borrowed = unsafe { Packet::new_borrowed(1, pkt.data_len(), &ring, LinkType::Ethernet).unwrap() };
+        let _ = borrowed.decode();
+        let weak = Rc::downgrade(&borrowed);
+        task.run(borrowed, dir.clone());
+        weak.upgrade()
+    };
+
+    run(&mut task, &build_pkt_syn(1));
+    assert!(run(&mut task, &build_pkt_line(2, *b"1234\r\n5678")).is_none());
+    run(&mut task, &build_pkt_line(12, *b"1234\r\n5678"));
+    assert_eq!(TaskState::Start, task.parser_state(dir.clone()));
+    run(&mut task, &build_pkt_fin(22));
+    assert_eq!(TaskState::End, task.parser_state(dir.clone()));
+}
+
+// 把以太网头换成其他链路层头
+fn relink(pkt: &Packet, link: &[u8]) -> Vec<u8> {
+    let mut data = link.to_vec();
+    data.extend_from_slice(&pkt[14..pkt.data_len()]);
+    data
+}
+
+// 非以太网的链路层
+#[test]
+fn test_link_types() {
+    let pkt4 = build_pkt(1, false);
+    let pkt6 = build_pkt6(1, false);
+
+    let mut sll = vec![0, 0, 0, 1, 0, 6, 1, 2, 3, 4, 5, 6, 0, 0, 0x08, 0x00];
+    let mut sll2 = vec![0x08, 0x00, 0, 0, 0, 0, 0, 2, 0, 1, 0, 6, 1, 2, 3, 4, 5, 6, 0, 0];
+    let cases = vec![
+        (LinkType::LinuxSll, relink(&pkt4, &sll)),
+        (LinkType::LinuxSll2, relink(&pkt4, &sll2)),
+        (LinkType::Null, relink(&pkt4, &2u32.to_le_bytes())),
+        (LinkType::Null, relink(&pkt4, &2u32.to_be_bytes())),
+        (LinkType::Null, relink(&pkt6, &30u32.to_le_bytes())),
+        (LinkType::Raw, relink(&pkt4, &[])),
+        (LinkType::Raw, relink(&pkt6, &[])),
+    ];
+    sll[14..16].copy_from_slice(&[0x86, 0xdd]);
+    sll2[0..2].copy_from_slice(&[0x86, 0xdd]);
+    let cases6 = vec![
+        (LinkType::LinuxSll, relink(&pkt6, &sll)),
+        (LinkType::LinuxSll2, relink(&pkt6, &sll2)),
+    ];
+
+    for (link_type, data) in cases.into_iter().chain(cases6) {
+        let pkt = Packet::new_with_link(1, data.len(), &data, link_type).unwrap();
+        assert_eq!(link_type, pkt.link_type());
+        assert!(pkt.decode().is_ok(), "{:?}", link_type);
+        assert_eq!(1, pkt.seq());
+        assert_eq!(10, pkt.payload_len());
+        let header = pkt.header.borrow();
+        let header = header.as_ref().unwrap();
+        assert!(header.link.is_none());
+        assert_eq!(&[1,2,3,4,5,6,7,8,9,10], &pkt[header.payload_offset..header.payload_offset + 10]);
+    }
+
+    // 链路层类型不对的，解码失败
+    let data = relink(&pkt4, &[]);
+    let pkt = Packet::new(1, data.len(), &data).unwrap();
+    assert!(pkt.decode().is_err());
+    let pkt = Packet::new_with_link(1, data.len(), &data, LinkType::Null).unwrap();
+    assert!(pkt.decode().is_err());
+}
+
+#[test]
+fn test_link_from_dlt() {
+    assert_eq!(Some(LinkType::Ethernet), LinkType::from_dlt(1));
+    assert_eq!(Some(LinkType::LinuxSll), LinkType::from_dlt(113));
+    assert_eq!(Some(LinkType::LinuxSll2), LinkType::from_dlt(276));
+    assert_eq!(Some(LinkType::Null), LinkType::from_dlt(0));
+    assert_eq!(Some(LinkType::Raw), LinkType::from_dlt(101));
+    assert_eq!(None, LinkType::from_dlt(105));
+}
+
+// tcp头的字段和选项
+#[test]
+fn test_tcp_fields() {
+    let pkt = build_pkt(1, false);
+    // 没有解码的包不会panic
+    assert_eq!(0, pkt.seq());
+    assert!(!pkt.syn());
+    assert_eq!(0, pkt.payload_len());
+    assert_eq!(None, pkt.ack_seq());
+    assert_eq!(None, pkt.rst());
+    assert_eq!(None, pkt.mss());
+
+    let _ = pkt.decode();
+    assert_eq!(Some(123), pkt.ack_seq());
+    assert_eq!(Some(true), pkt.ack());
+    assert_eq!(Some(false), pkt.rst());
+    assert_eq!(Some(false), pkt.psh());
+    assert_eq!(Some(true), pkt.urg());
+    assert_eq!(Some(1024), pkt.window());
+    assert_eq!(Some(1234), pkt.mss());
+    assert_eq!(None, pkt.wscale());
+    assert!(!pkt.sack_permitted());
+    assert_eq!(None, pkt.sack_blocks());
+    assert_eq!(None, pkt.tcp_ts());
+
+    let builder = PacketBuilder::
+    ethernet2([1,2,3,4,5,6], [7,8,9,10,11,12])
+        .ipv4([192,168,1,1], [192,168,1,2], 20)
+        .tcp(25, 4000, 1, 512)
+        .rst()
+        .psh()
+        .options(&[
+            TcpOptionElement::WindowScale(7),
+            TcpOptionElement::SelectiveAcknowledgementPermitted,
+            TcpOptionElement::SelectiveAcknowledgement((100, 200), [Some((300, 400)), None, None]),
+            TcpOptionElement::Timestamp(11, 22),
+        ]).unwrap();
+    let mut result = Vec::<u8>::with_capacity(builder.size(0));
+    builder.write(&mut result, &[]).unwrap();
+    let pkt = Packet::new(1, result.len(), &result).unwrap();
+    let _ = pkt.decode();
+    assert_eq!(None, pkt.ack_seq());
+    assert_eq!(Some(false), pkt.ack());
+    assert_eq!(Some(true), pkt.rst());
+    assert_eq!(Some(true), pkt.psh());
+    assert_eq!(Some(512), pkt.window());
+    assert_eq!(None, pkt.mss());
+    assert_eq!(Some(7), pkt.wscale());
+    assert!(pkt.sack_permitted());
+    assert_eq!(Some(vec![(100, 200), (300, 400)]), pkt.sack_blocks());
+    assert_eq!(Some((11, 22)), pkt.tcp_ts());
+}
+
+fn decode_err(data: &[u8]) -> PacketError {
+    Packet::new(1, data.len(), data).unwrap().decode().unwrap_err()
+}
+
+// 解码失败的原因和位置
+#[test]
+fn test_decode_err() {
+    let pkt = build_pkt(1, false);
+    let data = pkt.to_vec();
+
+    // ip头不完整
+    assert_eq!(PacketError::Truncated { offset: 30 }, decode_err(&data[..30]));
+    assert_eq!(PacketError::Truncated { offset: 10 }, decode_err(&data[..10]));
+
+    // arp
+    let mut arp = data.clone();
+    arp[12..14].copy_from_slice(&[0x08, 0x06]);
+    assert_eq!(PacketError::UnsupportedEtherType { offset: 14, ether_type: 0x0806 }, decode_err(&arp));
+
+    // 802.3的长度字段
+    let mut llc = data.clone();
+    llc[12..14].copy_from_slice(&[0x00, 0x40]);
+    assert_eq!(PacketError::NonIp { offset: 14 }, decode_err(&llc));
+
+    // ether type是ipv4，版本号不对
+    let mut ver = data.clone();
+    ver[14] = 0x55;
+    assert_eq!(PacketError::NonIp { offset: 14 }, decode_err(&ver));
+
+    // icmp
+    let mut icmp = data.clone();
+    icmp[23] = 1;
+    assert_eq!(PacketError::NonTcpUdp { offset: 34, proto: 1 }, decode_err(&icmp));
+
+    // 分片
+    let mut frag = data.clone();
+    frag[20] = 0x20;
+    assert_eq!(PacketError::Fragmented { offset: 34 }, decode_err(&frag));
+
+    // ipv4头长度小于20
+    let mut ihl = data.clone();
+    ihl[14] = 0x44;
+    assert_eq!(PacketError::MalformedOptions { offset: 14 }, decode_err(&ihl));
+
+    // tcp选项的长度超出了选项区
+    let mut opt = data.clone();
+    assert_eq!(&[1, 2, 4], &opt[54..57]);
+    opt[56] = 8;
+    assert_eq!(PacketError::MalformedOptions { offset: 54 }, decode_err(&opt));
+
+    // raw ip不是ip
+    let raw = Packet::new_with_link(1, 4, &[0x10, 0, 0, 0], LinkType::Raw).unwrap();
+    assert_eq!(Err(PacketError::NonIp { offset: 0 }), raw.decode());
+
+    let err = PacketError::UnsupportedEtherType { offset: 14, ether_type: 0x0806 };
+    assert_eq!(14, err.offset());
+    assert_eq!("unsupported ether type 0x0806 at offset 14", err.to_string());
+    let err: Box<dyn std::error::Error> = Box::new(PacketError::Truncated { offset: 30 });
+    assert_eq!("packet truncated at offset 30", err.to_string());
+}
@@ -4,6 +4,10 @@ use crate::common::*;
 use memerge::*;
 use std::env;
 use std::time::{SystemTime, UNIX_EPOCH};
+use futures::executor::block_on;
+use futures::FutureExt;
+use futures::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+use std::pin::Pin;
 
 const SMTP_PORT_NET: u16 = 25;
 
@@ -298,3 +302,271 @@ fn test_pcap_smtp() {
     let pkt = stm.pop_ord_data().unwrap();    
     assert_eq!(1341108891, pkt.seq());
 }
+
+// 1-10之后缺失11-20，缓存被21开始的包占满，再来包时跳过缺失
+#[test]
+fn test_gap_cache_full() {
+    let mut stm = PktStrm::new();
+    let pkt1 = build_pkt(1, false);
+    let _ = pkt1.decode();
+    stm.push(pkt1);
+    assert_eq!(1, stm.pop_ord_data().unwrap().seq());
+
+    for i in 0..33 {
+        let pkt = build_pkt(21 + i * 10, false);
+        let _ = pkt.decode();
+        stm.push(pkt);
+        if i < 32 {
+            assert!(!stm.has_gap());
+            assert_eq!(None, stm.peek_ord_pkt());
+        }
+    }
+    assert_eq!(33, stm.len());
+    assert_eq!(Some(Gap { seq: 11, len: 10 }), stm.take_gap());
+    assert_eq!(None, stm.take_gap());
+    assert_eq!(21, stm.pop_ord_data().unwrap().seq());
+    assert_eq!(31, stm.pop_ord_data().unwrap().seq());
+}
+
+// readline读到缺失时返回不完整的行，取走缺失之后继续读
+#[test]
+fn test_gap_readline() {
+    let mut stm = PktStrm::new();
+    let pkt1 = build_pkt_line(1, *b"abc\r\ndefgh");
+    let _ = pkt1.decode();
+    let pkt2 = build_pkt_line(21, *b"ij\r\nklmnop");
+    let _ = pkt2.decode();
+    stm.push(pkt1);
+    stm.push(pkt2);
+    stm.expire();
+
+    block_on(async {
+        assert_eq!("abc\r\n", stm.readline().await.unwrap());
+        assert_eq!("defgh", stm.readline().await.unwrap());
+        assert!(stm.has_gap());
+        assert_eq!("", stm.readline().await.unwrap());
+        assert_eq!(Some(Gap { seq: 11, len: 10 }), stm.take_gap());
+        assert_eq!("ij\r\n", stm.readline().await.unwrap());
+        assert_eq!(b"klm".to_vec(), stm.readn(3).await);
+        assert_eq!("nop", stm.readline().await.unwrap());
+        assert_eq!("", stm.readline().await.unwrap());
+        assert!(!stm.has_gap());
+    });
+}
+
+// 按块读取：部分消费，跨包，读到末尾和fin
+#[test]
+fn test_chunk() {
+    let mut stm = PktStrm::new();
+    let pkt1 = build_pkt_line(1, *b"abc\r\ndefgh");
+    let _ = pkt1.decode();
+    let pkt2 = build_pkt_line(11, *b"ij\r\nklmnop");
+    let _ = pkt2.decode();
+    let pkt3 = build_pkt_fin(21);
+    let _ = pkt3.decode();
+    stm.push(pkt1);
+    stm.push(pkt2);
+
+    let chunk = stm.peek_chunk().unwrap();
+    assert_eq!(1, chunk.seq());
+    assert_eq!(b"abc\r\ndefgh", &chunk[..]);
+    stm.consume(3);
+    let chunk = stm.peek_chunk().unwrap();
+    assert_eq!(4, chunk.seq());
+    assert_eq!(b"\r\ndefgh", &chunk[..]);
+
+    block_on(async {
+        assert_eq!(b"\r\nde", &stm.next_chunk(4).await.unwrap()[..]);
+        assert_eq!(b"fgh", &stm.next_chunk(100).await.unwrap()[..]);
+        let chunk = stm.next_chunk(100).await.unwrap();
+        assert_eq!(11, chunk.seq());
+        assert_eq!(b"ij\r\nklmnop", &chunk[..]);
+    });
+
+    stm.push(pkt3);
+    assert!(stm.peek_chunk().is_none());
+    block_on(async {
+        assert!(stm.next_chunk(100).await.is_none());
+    });
+}
+
+// readn和readline跨越多个包
+#[test]
+fn test_chunk_read() {
+    let mut stm = PktStrm::new();
+    for (seq, payload) in [(1, *b"ab\r\ncdefgh"), (11, *b"ijklmnopqr"), (21, *b"st\r\nuvwxyz")] {
+        let pkt = build_pkt_line(seq, payload);
+        let _ = pkt.decode();
+        stm.push(pkt);
+    }
+    let pkt = build_pkt_fin(31);
+    let _ = pkt.decode();
+    stm.push(pkt);
+
+    block_on(async {
+        assert_eq!(b"ab".to_vec(), stm.readn(2).await);
+        assert_eq!("\r\n", stm.readline().await.unwrap());
+        assert_eq!("cdefghijklmnopqrst\r\n", stm.readline().await.unwrap());
+        assert_eq!(b"uvwxyz".to_vec(), stm.readn(10).await);
+        assert_eq!("", stm.readline().await.unwrap());
+    });
+}
+
+// 通过AsyncRead/AsyncBufRead读取，遇到缺失时读到0字节
+#[test]
+fn test_async_read() {
+    let mut stm = PktStrm::new();
+    for (seq, payload) in [(1, *b"ab\r\ncdefgh"), (11, *b"ijklmnopqr"), (31, *b"st\r\nuvwxyz")] {
+        let pkt = build_pkt_line(seq, payload);
+        let _ = pkt.decode();
+        stm.push(pkt);
+    }
+    stm.expire();
+
+    block_on(async {
+        let mut line = String::new();
+        assert_eq!(4, stm.read_line(&mut line).await.unwrap());
+        assert_eq!("ab\r\n", line);
+
+        let mut buf = [0; 8];
+        stm.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"cdefghij", &buf);
+
+        let mut rest = Vec::new();
+        assert_eq!(8, stm.read_to_end(&mut rest).await.unwrap());
+        assert_eq!(b"klmnopqr".to_vec(), rest);
+        assert_eq!(Some(Gap { seq: 21, len: 10 }), stm.take_gap());
+
+        let mut rest = Vec::new();
+        assert_eq!(4, AsyncBufReadExt::read_until(&mut stm, b'\n', &mut rest).await.unwrap());
+        assert_eq!(b"st\r\n".to_vec(), rest);
+        assert_eq!(b"uvwxyz", stm.fill_buf().await.unwrap());
+        Pin::new(&mut stm).consume(6);
+        assert_eq!(0, stm.read(&mut buf).await.unwrap());
+    });
+}
+
+// 非utf8的行，按分隔符读取，超长的行，\r\n结尾的行
+#[test]
+fn test_read_line_bytes() {
+    let mut stm = PktStrm::new();
+    for (seq, payload) in [(1, *b"a\xff\r\nb:cdef"), (11, *b"ghijk\r\nl\nm"), (21, *b"\r\nnopqrstu")] {
+        let pkt = build_pkt_line(seq, payload);
+        let _ = pkt.decode();
+        stm.push(pkt);
+    }
+    let pkt = build_pkt_fin(31);
+    let _ = pkt.decode();
+    stm.push(pkt);
+
+    block_on(async {
+        assert_eq!(b"a\xff\r\n".to_vec(), stm.read_line_bytes().await);
+        assert_eq!(b"b:".to_vec(), stm.read_until(b':').await);
+        assert_eq!(Err(LineTooLong { max: 5 }), stm.read_line_limited(5).await);
+        assert_eq!(Ok(b"hijk\r\n".to_vec()), stm.read_line_limited(6).await);
+        assert_eq!(Ok(b"l\nm\r\n".to_vec()), stm.read_crlf_line(100).await);
+        assert_eq!(Err(LineTooLong { max: 4 }), stm.read_crlf_line(4).await);
+        assert_eq!(Ok(b"rstu".to_vec()), stm.read_line_limited(100).await);
+        assert_eq!(Ok(Vec::new()), stm.read_crlf_line(100).await);
+    });
+
+    assert_eq!(b"abc", trim_crlf(b"abc\r\n"));
+    assert_eq!(b"abc", trim_crlf(b"abc\n"));
+    assert_eq!(b"abc\r", trim_crlf(b"abc\r\r\n"));
+    assert_eq!(b"abc", trim_crlf(b"abc"));
+}
+
+// peek跨包查看数据，不移动读的位置。数据不够时等待
+#[test]
+fn test_peek() {
+    let mut stm = PktStrm::new();
+    let pkt1 = build_pkt_line(1, *b"abc\r\ndefgh");
+    let _ = pkt1.decode();
+    let pkt2 = build_pkt_line(11, *b"ij\r\nklmnop");
+    let _ = pkt2.decode();
+    let pkt3 = build_pkt_line(21, *b"qrstuvwxyz");
+    let _ = pkt3.decode();
+    let pkt4 = build_pkt_fin(31);
+    let _ = pkt4.decode();
+    stm.push(pkt1);
+    stm.push(pkt3);
+
+    assert_eq!(Some(b"abc\r".to_vec()), stm.peek(4).now_or_never());
+    assert_eq!(Some(b"abc\r\n".to_vec()), stm.peek_until(b'\n').now_or_never());
+    assert_eq!(None, stm.peek(15).now_or_never());
+    assert_eq!(Some("abc\r\n".to_string()), stm.readline().now_or_never().map(Result::unwrap));
+
+    stm.push(pkt2);
+    assert_eq!(Some(b"defghij\r\n".to_vec()), stm.peek_until(b'\n').now_or_never());
+    assert_eq!(Some(b"defghij\r\nklmnopqrs".to_vec()), stm.peek(18).now_or_never());
+    assert_eq!(None, stm.peek(100).now_or_never());
+
+    stm.push(pkt4);
+    let rest = b"defghij\r\nklmnopqrstuvwxyz".to_vec();
+    assert_eq!(Some(rest.clone()), stm.peek(100).now_or_never());
+    assert_eq!(Some(rest), stm.readn(100).now_or_never());
+    assert_eq!(Some(Vec::new()), stm.peek(1).now_or_never());
+}
+
+// 序号回绕：MAX-9到MAX，0到9，10到19。乱序放入，按回绕后的顺序取出
+#[test]
+fn test_wrap_order() {
+    let seq1 = u32::MAX - 9;
+    let mut stm = PktStrm::new();
+    for seq in [10, 0, seq1] {
+        let pkt = build_pkt(seq, false);
+        let _ = pkt.decode();
+        stm.push(pkt);
+    }
+    assert_eq!(Some(seq1), stm.isn());
+
+    assert_eq!(seq1, stm.pop_ord_data().unwrap().seq());
+    assert_eq!(0, stm.pop_ord_data().unwrap().seq());
+
+    // 回绕之前的重传被去重
+    let pkt = build_pkt(seq1, false);
+    let _ = pkt.decode();
+    stm.push(pkt);
+    assert_eq!(10, stm.pop_ord_data().unwrap().seq());
+    assert_eq!(None, stm.pop_ord_data());
+    assert!(stm.is_empty());
+}
+
+// syn在MAX，数据从0开始
+#[test]
+fn test_wrap_syn() {
+    let mut stm = PktStrm::new();
+    let pkt1 = build_pkt(0, false);
+    let _ = pkt1.decode();
+    let syn = build_pkt_syn(u32::MAX);
+    let _ = syn.decode();
+    stm.push(pkt1);
+    stm.push(syn.clone());
+    assert_eq!(Some(u32::MAX), stm.isn());
+
+    assert_eq!(u32::MAX, stm.pop_ord_pkt().unwrap().seq());
+    // 重传的syn不影响顺序
+    stm.push(syn);
+    assert_eq!(0, stm.pop_ord_data().unwrap().seq());
+    assert_eq!(None, stm.pop_ord_data());
+}
+
+// 一个包内部回绕，readline跨过回绕点
+#[test]
+fn test_wrap_readline() {
+    let mut stm = PktStrm::new();
+    let pkt1 = build_pkt_line(u32::MAX - 4, *b"abcde\r\nfgh");
+    let _ = pkt1.decode();
+    let pkt2 = build_pkt_line(5, *b"ij\r\nklmno\n");
+    let _ = pkt2.decode();
+    stm.push(pkt2);
+    stm.push(pkt1);
+
+    block_on(async {
+        assert_eq!("abcde\r\n", stm.readline().await.unwrap());
+        assert_eq!("fghij\r\n", stm.readline().await.unwrap());
+        assert_eq!("klmno\n", stm.readline().await.unwrap());
+    });
+    assert_eq!(None, stm.pop_ord_data());
+    assert!(stm.is_empty());
+}
@@ -0,0 +1,119 @@
+mod common;
+
+use etherparse::{PacketBuilder, TcpOptionElement};
+use futures::executor::block_on;
+use futures_channel::mpsc;
+use futures_util::SinkExt;
+use core::{future::Future, pin::Pin};
+use memerge::*;
+use memerge::smtp::MetaSmtp;
+use std::rc::Rc;
+use crate::common::*;
+
+// 带窗口扩大因子的syn
+fn syn_wscale(src: ([u8;4], u16), dst: ([u8;4], u16), seq: u32, ack: Option<u32>, scale: u8) -> Rc<Packet> {
+    let mut builder = PacketBuilder::
+    ethernet2([1,2,3,4,5,6], [7,8,9,10,11,12])
+        .ipv4(src.0, dst.0, 20)
+        .tcp(src.1, dst.1, seq, 1024)
+        .syn();
+    if let Some(ack) = ack {
+        builder = builder.ack(ack);
+    }
+    let builder = builder.options(&[TcpOptionElement::WindowScale(scale), TcpOptionElement::Noop]).unwrap();
+    let mut result = Vec::<u8>::with_capacity(builder.size(0));
+    builder.write(&mut result, &[]).unwrap();
+    let pkt = Packet::new(1, result.len(), &result).unwrap();
+    pkt.decode().unwrap();
+    pkt
+}
+
+// 读一行，行尾之外再报告流是否被rst
+struct RstParser;
+impl Parser for RstParser {
+    fn c2s_parser(&self, stream: *const PktStrm, mut meta_tx: mpsc::Sender<Meta>) -> Pin<Box<dyn Future<Output = ()>>> {
+        Box::pin(async move {
+            let stm: &mut PktStrm;
+            unsafe { stm = &mut *(stream as *mut PktStrm); }
+
+            let line = stm.readline().await.unwrap();
+            let _ = meta_tx.send(Meta::Smtp(MetaSmtp::User(line))).await;
+            let _ = meta_tx.send(Meta::Smtp(MetaSmtp::Pass(stm.is_rst().to_string()))).await;
+        })
+    }
+}
+
+// rst结束流，缓存中的数据还能读完
+#[test]
+fn test_rst_strm() {
+    let mut stm = PktStrm::new();
+    stm.push(c2s(101, 1001, "A", b"HELO"));
+    stm.push(c2s(110, 1001, "A", b"QUIT"));
+    stm.push(c2s(114, 1001, "R", &[]));
+    assert!(stm.is_rst());
+
+    block_on(async {
+        assert_eq!("HELO", stm.readline().await.unwrap());
+        assert_eq!(Some(Gap { seq: 105, len: 5 }), stm.take_gap());
+        assert_eq!("QUIT", stm.readline().await.unwrap());
+        assert_eq!("", stm.readline().await.unwrap());
+    });
+}
+
+// 对端窗口外的rst被忽略，报告异常
+#[test]
+fn test_rst_window() {
+    let mut stm = PktStrm::new();
+    stm.push(c2s(101, 1001, "A", b"HELO\r\n"));
+    stm.update_peer(107, 100);
+    stm.push(c2s(106, 1001, "R", &[]));
+    stm.push(c2s(207, 1001, "R", &[]));
+    assert!(!stm.is_rst());
+    assert_eq!(Some(Anomaly::BadRst { seq: 106 }), stm.take_anomaly());
+    assert_eq!(Some(Anomaly::BadRst { seq: 207 }), stm.take_anomaly());
+
+    stm.push(c2s(206, 1001, "R", &[]));
+    assert!(stm.is_rst());
+    assert_eq!(None, stm.take_anomaly());
+}
+
+// 服务器的rst结束两个方向，等待中的解析器结束，状态为Reset
+#[test] #[cfg(not(miri))]
+fn test_rst_task() {
+    let mut task = Task::new_with_parser(RstParser);
+    task.run(c2s(100, 1001, "S", &[]), PktDirection::Unknown);
+    task.run(s2c(1000, 101, "SA", &[]), PktDirection::Unknown);
+    task.run(c2s(101, 1001, "AP", b"HEL"), PktDirection::Unknown);
+    assert_eq!(TaskState::Start, task.parser_state(PktDirection::Client2Server));
+
+    // 窗口外的无效
+    task.run(s2c(999, 101, "R", &[]), PktDirection::Unknown);
+    assert!(!task.is_reset());
+    assert_eq!(TaskState::Start, task.parser_state(PktDirection::Client2Server));
+    assert_eq!(Some((PktDirection::Server2Client, Anomaly::BadRst { seq: 999 })), task.get_anomaly());
+
+    task.run(s2c(1001, 101, "R", &[]), PktDirection::Unknown);
+    assert!(task.is_reset());
+    assert_eq!(TaskState::Reset, task.parser_state(PktDirection::Client2Server));
+    assert_eq!(TaskState::End, task.parser_state(PktDirection::Server2Client));
+    let mut metas = Vec::new();
+    while let Some(Meta::Smtp(meta)) = task.get_meta() {
+        metas.push(format!("{:?}", meta));
+    }
+    assert_eq!(vec!["User(\"HEL\")", "Pass(\"true\")"], metas);
+}
+
+// 两端的syn都带了窗口扩大因子，窗口按因子换算
+#[test] #[cfg(not(miri))]
+fn test_rst_wscale() {
+    let mut task = Task::new();
+    task.run(syn_wscale(CLIENT, SERVER, 100, None, 7), PktDirection::Unknown);
+    task.run(syn_wscale(SERVER, CLIENT, 1000, Some(101), 7), PktDirection::Unknown);
+    task.run(c2s(101, 1001, "A", &[]), PktDirection::Unknown);
+
+    // 1024 << 7
+    task.run(s2c(1001 + 131072, 101, "R", &[]), PktDirection::Unknown);
+    assert!(!task.is_reset());
+    task.run(s2c(1001 + 131071, 101, "R", &[]), PktDirection::Unknown);
+    assert!(task.is_reset());
+}
@@ -132,6 +132,24 @@ fn test_smtp_parser() {
     }
 }
 
+// 两个方向的包都不区分方向交给task，由task判断客户端
+#[test]
+fn test_smtp_parser_unknown_dir() {
+    let project_root = env::current_dir().unwrap();
+    let file_path = project_root.join("tests/smtp.pcap");
+    let mut cap = Capture::init(file_path).unwrap();
+    let mut task = Task::new_with_parser(SmtpParser);
+
+    while let Some(pkt) = cap.next_packet(1) {
+        if pkt.decode().is_err() {
+            continue;
+        }
+        task.run(pkt, PktDirection::Unknown);
+        meta_recver(&mut task);
+    }
+    assert_eq!(SMTP_PORT_NET, task.client().unwrap().dport);
+}
+
 fn meta_recver(task: &mut Task) {
     while let Some(meta) = task.get_meta() {
         match meta {
@@ -160,3 +178,81 @@ fn meta_smtp_recver(smtp: MetaSmtp) {
         }
     }
 }
+
+// 两个方向的包都交给流表，由流表区分连接和方向
+#[test]
+fn test_smtp_flow_table() {
+    let project_root = env::current_dir().unwrap();
+    let file_path = project_root.join("tests/smtp.pcap");
+    let mut cap = Capture::init(file_path).unwrap();
+    let mut flow_table = FlowTable::new(|key| {
+        if key.sport == SMTP_PORT_NET || key.dport == SMTP_PORT_NET {
+            Some(Box::new(SmtpParser))
+        } else {
+            None
+        }
+    });
+
+    let mut metas = 0;
+    while let Some(pkt) = cap.next_packet(1) {
+        let _ = flow_table.run(pkt);
+        while let Some((key, meta)) = flow_table.get_meta() {
+            assert_eq!(SMTP_PORT_NET, key.dport);
+            match meta {
+                memerge::Meta::Smtp(smtp) => meta_smtp_recver(smtp),
+                memerge::Meta::Http(_) => {}
+            }
+            metas += 1;
+        }
+    }
+    assert!(metas >= 5);
+}
+
+fn smtp_metas(task: &mut Task) -> Vec<String> {
+    std::iter::from_fn(|| task.get_meta()).map(|meta| match meta {
+        Meta::Smtp(smtp) => format!("{:?}", smtp),
+        Meta::Http(_) => String::new(),
+    }).collect()
+}
+
+// 缺失之后丢掉前后不完整的行，从下一行接着解析，不把缺失当作空行
+#[test] #[cfg(not(miri))]
+fn test_smtp_parser_gap() {
+    let mut task = Task::new_with_parser(SmtpParser);
+    task.set_timeout(1000);
+    let cmds = b"EHLO a\r\nAUTH LOGIN\r\nuser\r\npass\r\nMAIL FROM: <a@b.c> SIZE=10\r\nRCPT TO: <d@e.f>\r\nDATA\r\nTo";
+    let seq = 101 + cmds.len() as u32;
+    task.run(c2s(100, 1001, "S", &[]), PktDirection::Client2Server);
+    task.run(c2s(101, 1001, "AP", cmds), PktDirection::Client2Server);
+    task.run(c2s(seq + 4, 1001, "AP", b"f>\r\nSubject: hi\r\n\r\n"), PktDirection::Client2Server);
+    assert_eq!(vec![
+        "User(\"user\")",
+        "Pass(\"pass\")",
+        "MailFrom(\"a@b.c\", 10)",
+        "RcptTo(\"d@e.f\")",
+    ], smtp_metas(&mut task));
+
+    assert!(task.timeout(2000));
+    assert_eq!(vec!["Subject(\"hi\")"], smtp_metas(&mut task));
+    assert_eq!(1, task.stats(PktDirection::Client2Server).gaps);
+    assert_eq!(TaskState::End, task.parser_state(PktDirection::Client2Server));
+}
+
+// 流提前结束时解析器直接结束，不产生空的命令
+#[test] #[cfg(not(miri))]
+fn test_smtp_parser_early_fin() {
+    let mut task = Task::new_with_parser(SmtpParser);
+    task.run(c2s(100, 1001, "S", &[]), PktDirection::Client2Server);
+    task.run(c2s(101, 1001, "AP", b"EHLO a\r\nAUTH LOGIN\r\n"), PktDirection::Client2Server);
+    task.run(c2s(121, 1001, "AF", &[]), PktDirection::Client2Server);
+    assert!(smtp_metas(&mut task).is_empty());
+    assert_eq!(TaskState::End, task.parser_state(PktDirection::Client2Server));
+}
+
+// 中途接入时丢掉第一行不完整的部分
+#[test] #[cfg(not(miri))]
+fn test_smtp_parser_midstream() {
+    let mut task = Task::new_with_parser(SmtpParser);
+    task.run(c2s(11, 1001, "AP", b"O a\r\nEHLO b\r\nAUTH LOGIN\r\nuser\r\npass\r\n"), PktDirection::Client2Server);
+    assert_eq!(vec!["User(\"user\")", "Pass(\"pass\")"], smtp_metas(&mut task));
+}
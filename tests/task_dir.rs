@@ -0,0 +1,75 @@
+mod common;
+
+use memerge::*;
+use memerge::smtp::MetaSmtp;
+use std::net::IpAddr;
+use crate::common::*;
+
+fn metas(task: &mut Task) -> Vec<String> {
+    let mut ret = Vec::new();
+    while let Some(meta) = task.get_meta() {
+        match meta {
+            Meta::Smtp(MetaSmtp::User(line)) => ret.push(format!("c2s {}", line)),
+            Meta::Smtp(MetaSmtp::Pass(line)) => ret.push(format!("s2c {}", line)),
+            _ => {}
+        }
+    }
+    ret
+}
+
+fn check_client(task: &Task, client: ([u8;4], u16)) {
+    let key = task.client().unwrap();
+    assert_eq!(IpAddr::from(client.0), key.saddr);
+    assert_eq!(client.1, key.sport);
+}
+
+// syn决定客户端
+#[test] #[cfg(not(miri))]
+fn test_dir_syn() {
+    let mut task = Task::new_with_parser(LineParser);
+    assert!(task.client().is_none());
+    task.run(tcp(CLIENT, SERVER, 100, 0, "S", &[]), PktDirection::Unknown);
+    check_client(&task, CLIENT);
+
+    task.run(tcp(SERVER, CLIENT, 1000, 101, "SA", &[]), PktDirection::Unknown);
+    task.run(tcp(SERVER, CLIENT, 1001, 101, "AP", b"220 ok\r\n"), PktDirection::BiDirection);
+    task.run(tcp(CLIENT, SERVER, 101, 1009, "AP", b"HELO\r\n"), PktDirection::Unknown);
+    assert_eq!(vec!["s2c 220 ok\r\n", "c2s HELO\r\n"], metas(&mut task));
+}
+
+// 先看到syn+ack，接收方是客户端
+#[test] #[cfg(not(miri))]
+fn test_dir_synack() {
+    let mut task = Task::new_with_parser(LineParser);
+    task.run(tcp(SERVER, CLIENT, 1000, 101, "SA", &[]), PktDirection::Unknown);
+    check_client(&task, CLIENT);
+}
+
+// 没有握手，服务器先发包，按端口判断
+#[test] #[cfg(not(miri))]
+fn test_dir_port() {
+    let mut task = Task::new_with_parser(LineParser);
+    task.run(tcp(SERVER, CLIENT, 1001, 101, "AP", b"220 ok\r\n"), PktDirection::Unknown);
+    check_client(&task, CLIENT);
+    assert_eq!(vec!["s2c 220 ok\r\n"], metas(&mut task));
+
+    // 两端都不是服务端口，先发包的是客户端
+    let peer = ([192,168,1,1], 50000);
+    let mut task = Task::new_with_parser(LineParser);
+    task.run(tcp(peer, CLIENT, 1001, 101, "AP", b"hello\r\n"), PktDirection::Unknown);
+    check_client(&task, peer);
+    assert_eq!(vec!["c2s hello\r\n"], metas(&mut task));
+
+    // 不属于这个连接的包被忽略
+    task.run(tcp(SERVER, CLIENT, 1001, 101, "AP", b"other\r\n"), PktDirection::Unknown);
+    assert_eq!(0, task.steeam_len(PktDirection::Server2Client));
+}
+
+// 调用者给出方向时，按给出的方向记录客户端
+#[test] #[cfg(not(miri))]
+fn test_dir_given() {
+    let mut task = Task::new_with_parser(LineParser);
+    task.run(tcp(CLIENT, SERVER, 101, 1001, "AP", b"HELO\r\n"), PktDirection::Server2Client);
+    check_client(&task, SERVER);
+    assert_eq!(vec!["s2c HELO\r\n"], metas(&mut task));
+}
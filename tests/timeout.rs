@@ -0,0 +1,62 @@
+mod common;
+
+use memerge::*;
+use crate::common::*;
+
+const TIMEOUT: u128 = 1000;
+
+// 缺失数据时阻塞在readline的解析器，超时之后跳过缺失的数据，读完结束。
+// 缺失之前不完整的行单独返回
+#[test] #[cfg(not(miri))]
+fn test_task_timeout() {
+    let mut task = Task::new_with_parser(LinesParser);
+    task.set_timeout(TIMEOUT);
+    task.run(c2s(100, 1001, "S", &[]), PktDirection::Client2Server);
+    task.run(c2s(101, 1001, "AP", b"HEL"), PktDirection::Client2Server);
+    task.run(c2s(110, 1001, "AP", b"QUIT\r\n"), PktDirection::Client2Server);
+    assert!(task.get_meta().is_none());
+
+    assert!(!task.timeout(1 + TIMEOUT));
+    assert_eq!(TaskState::Start, task.parser_state(PktDirection::Client2Server));
+    assert!(task.timeout(2 + TIMEOUT));
+    assert_eq!(vec!["HEL", "QUIT\r\n"], lines(&mut task));
+    assert_eq!(TaskState::End, task.parser_state(PktDirection::Client2Server));
+    assert!(!task.timeout(3 + TIMEOUT));
+}
+
+// 流表超时时先收集解析器最后的meta，再删除连接
+#[test] #[cfg(not(miri))]
+fn test_flow_table_timeout() {
+    let mut flow_table = FlowTable::new(|_| Some(Box::new(LinesParser)));
+    flow_table.set_strm_timeout(TIMEOUT);
+    flow_table.set_timeout(TIMEOUT * 2);
+    let key = flow_table.run(build_tcp(CLIENT, SERVER, 100, 0, "S", &[])).unwrap().unwrap();
+    flow_table.run(build_tcp(CLIENT, SERVER, 101, 1001, "AP", b"MAIL")).unwrap();
+    assert!(flow_table.get_meta().is_none());
+
+    flow_table.timeout(2 + TIMEOUT);
+    assert_eq!(1, flow_table.len());
+    let (meta_key, meta) = flow_table.get_meta().unwrap();
+    assert_eq!(key, meta_key);
+    assert_eq!(vec!["MAIL"], users([meta]));
+
+    flow_table.timeout(2 + TIMEOUT * 2);
+    assert!(flow_table.is_empty());
+}
+
+// 连接空闲超时早于流超时，删除之前也结束流，收集解析器最后的meta
+#[test] #[cfg(not(miri))]
+fn test_flow_table_idle_drain() {
+    let mut flow_table = FlowTable::new(|_| Some(Box::new(LinesParser)));
+    flow_table.set_strm_timeout(TIMEOUT * 10);
+    flow_table.set_timeout(TIMEOUT);
+    let key = flow_table.run(build_tcp(CLIENT, SERVER, 100, 0, "S", &[])).unwrap().unwrap();
+    flow_table.run(build_tcp(CLIENT, SERVER, 101, 1001, "AP", b"MAIL")).unwrap();
+
+    flow_table.timeout(2 + TIMEOUT);
+    assert!(flow_table.is_empty());
+    let (meta_key, meta) = flow_table.get_meta().unwrap();
+    assert_eq!(key, meta_key);
+    assert_eq!(vec!["MAIL"], users([meta]));
+    assert!(flow_table.get_meta().is_none());
+}
@@ -0,0 +1,160 @@
+mod common;
+
+use etherparse::*;
+use memerge::*;
+use std::rc::Rc;
+use crate::common::*;
+
+const ETH_LEN: usize = 14;
+
+// 外层：以太网 + ipv4 + udp
+fn outer_udp(dport: u16, payload: &[u8]) -> Vec<u8> {
+    let builder = PacketBuilder::
+    ethernet2([0xa,0xb,0xc,0xd,0xe,0xf], [0xf,0xe,0xd,0xc,0xb,0xa])
+        .ipv4([10,0,0,1], [10,0,0,2], 64)
+        .udp(50000, dport);
+    let mut result = Vec::<u8>::with_capacity(builder.size(payload.len()));
+    builder.write(&mut result, payload).unwrap();
+    result
+}
+
+// 外层：以太网 + ipv4，ip协议号为gre
+fn outer_gre(gre: &[u8], inner: &[u8]) -> Vec<u8> {
+    let builder = PacketBuilder::
+    ethernet2([0xa,0xb,0xc,0xd,0xe,0xf], [0xf,0xe,0xd,0xc,0xb,0xa])
+        .ipv4([10,0,0,1], [10,0,0,2], 64);
+    let mut payload = gre.to_vec();
+    payload.extend_from_slice(inner);
+    let mut result = Vec::<u8>::with_capacity(builder.size(payload.len()));
+    builder.write(&mut result, 47, &payload).unwrap();
+    result
+}
+
+fn to_pkt(data: &[u8]) -> Rc<Packet> {
+    Packet::new(1, data.len(), data).unwrap()
+}
+
+// 内层的tcp包被正确解出来
+fn check_inner(pkt: &Packet) {
+    assert!(pkt.decode().is_ok());
+    assert_eq!(1, pkt.seq());
+    assert_eq!(10, pkt.payload_len());
+    let header = pkt.header.borrow();
+    let header = header.as_ref().unwrap();
+    assert_eq!(25, header.sport());
+    assert_eq!(4000, header.dport());
+    assert_eq!(&[1,2,3,4,5,6,7,8,9,10], &pkt[header.payload_offset..header.payload_offset + 10]);
+}
+
+fn tunnels(pkt: &Packet) -> Vec<(TunnelType, Option<u32>)> {
+    let header = pkt.header.borrow();
+    header.as_ref().unwrap().tunnels.iter().map(|t| (t.tunnel_type, t.id)).collect()
+}
+
+#[test]
+fn test_vxlan() {
+    let inner = build_pkt(1, false);
+    let mut payload = vec![0x08, 0, 0, 0, 0, 0x12, 0x34, 0];
+    payload.extend_from_slice(&inner);
+
+    let pkt = to_pkt(&outer_udp(4789, &payload));
+    check_inner(&pkt);
+    assert_eq!(vec![(TunnelType::Vxlan, Some(0x1234))], tunnels(&pkt));
+    assert_eq!(Some(0x1234), pkt.header.borrow().as_ref().unwrap().tunnel_id());
+    assert!(pkt.header.borrow().as_ref().unwrap().tunnels[0].ip.is_some());
+
+    // 没有I标志的不当作vxlan，解出来的是外层的udp
+    payload[0] = 0;
+    let pkt = to_pkt(&outer_udp(4789, &payload));
+    assert!(pkt.decode().is_ok());
+    assert!(tunnels(&pkt).is_empty());
+    assert_eq!(4789, pkt.header.borrow().as_ref().unwrap().dport());
+}
+
+#[test]
+fn test_geneve() {
+    let inner = build_pkt(1, false);
+    // 一个4字节的option
+    let mut payload = vec![0x01, 0, 0x65, 0x58, 0, 0, 0x07, 0, 1, 2, 3, 0];
+    payload.extend_from_slice(&inner);
+
+    let pkt = to_pkt(&outer_udp(6081, &payload));
+    check_inner(&pkt);
+    assert_eq!(vec![(TunnelType::Geneve, Some(7))], tunnels(&pkt));
+}
+
+#[test]
+fn test_gtpu() {
+    let inner = build_pkt(1, false);
+    let ip = &inner[ETH_LEN..];
+    let payload = [&[0x30, 0xff, 0, 0, 0, 0, 0, 9][..], ip].concat();
+    let pkt = to_pkt(&outer_udp(2152, &payload));
+    check_inner(&pkt);
+    assert_eq!(vec![(TunnelType::GtpU, Some(9))], tunnels(&pkt));
+
+    // 带一个扩展头
+    let payload = [&[0x34, 0xff, 0, 0, 0, 0, 0, 9, 0, 0, 0, 0x85, 1, 0, 0, 0][..], ip].concat();
+    let pkt = to_pkt(&outer_udp(2152, &payload));
+    check_inner(&pkt);
+}
+
+#[test]
+fn test_gre() {
+    let inner = build_pkt(1, false);
+    let ip = &inner[ETH_LEN..];
+
+    let pkt = to_pkt(&outer_gre(&[0, 0, 0x08, 0x00], ip));
+    check_inner(&pkt);
+    assert_eq!(vec![(TunnelType::Gre, None)], tunnels(&pkt));
+    assert_eq!(None, pkt.header.borrow().as_ref().unwrap().tunnel_id());
+
+    // 带key和seq，内层是以太网
+    let pkt = to_pkt(&outer_gre(&[0x30, 0, 0x65, 0x58, 0, 0, 0, 5, 0, 0, 0, 1], &inner));
+    check_inner(&pkt);
+    assert_eq!(vec![(TunnelType::Gre, Some(5))], tunnels(&pkt));
+}
+
+#[test]
+fn test_mpls() {
+    let inner = build_pkt(1, false);
+    let ip = &inner[ETH_LEN..];
+
+    // 两层label，栈底label是100
+    let mut data = inner[..12].to_vec();
+    data.extend_from_slice(&[0x88, 0x47]);
+    data.extend_from_slice(&(200u32 << 12 | 64).to_be_bytes());
+    data.extend_from_slice(&(100u32 << 12 | 0x100 | 64).to_be_bytes());
+    data.extend_from_slice(ip);
+    let pkt = to_pkt(&data);
+    check_inner(&pkt);
+    assert_eq!(vec![(TunnelType::Mpls, Some(100))], tunnels(&pkt));
+    assert!(pkt.header.borrow().as_ref().unwrap().tunnels[0].ip.is_none());
+
+    // gre里面的mpls
+    let mut mpls = (100u32 << 12 | 0x100 | 64).to_be_bytes().to_vec();
+    mpls.extend_from_slice(ip);
+    let pkt = to_pkt(&outer_gre(&[0, 0, 0x88, 0x47], &mpls));
+    check_inner(&pkt);
+    assert_eq!(vec![(TunnelType::Gre, None), (TunnelType::Mpls, Some(100))], tunnels(&pkt));
+    assert_eq!(Some(100), pkt.header.borrow().as_ref().unwrap().tunnel_id());
+}
+
+// 超过MAX_TUNNEL_DEPTH层的解码失败
+#[test]
+fn test_tunnel_depth() {
+    let mut frame = build_pkt(1, false).to_vec();
+    for depth in 1..=MAX_TUNNEL_DEPTH + 1 {
+        let mut payload = vec![0x08, 0, 0, 0, 0, 0, depth as u8, 0];
+        payload.extend_from_slice(&frame);
+        frame = outer_udp(4789, &payload);
+
+        let pkt = to_pkt(&frame);
+        if depth <= MAX_TUNNEL_DEPTH {
+            check_inner(&pkt);
+            assert_eq!(depth, tunnels(&pkt).len());
+            assert_eq!(Some(1), pkt.header.borrow().as_ref().unwrap().tunnel_id());
+        } else {
+            assert!(pkt.decode().is_err());
+        }
+    }
+}